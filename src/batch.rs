@@ -0,0 +1,372 @@
+use std::{
+    collections::HashSet,
+    io::{self, Write},
+    pin::Pin,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll, ready},
+};
+
+use axum::body::Bytes;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::io::{AsyncRead, AsyncWriteExt, DuplexStream, ReadBuf};
+use zip::{
+    CompressionMethod, ZipWriter,
+    write::{SimpleFileOptions, StreamWriter},
+};
+
+use crate::diagnostics::Diagnostic;
+
+/// Name of the manifest in a batch archive.
+const MANIFEST_NAME: &str = "manifest.json";
+
+/// Zip archive of a batch, written while the entries of the batch are rendered.
+///
+/// Every added file returns the bytes written since the previous file, so the archive can be
+/// sent before the whole batch is rendered.
+pub struct BatchArchive {
+    zip: ZipWriter<StreamWriter<SharedBuffer>>,
+    buffer: Arc<Mutex<Vec<u8>>>,
+}
+
+/// Buffer that the zip writer writes to, drained after every file.
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+/// Outcome of all entries of a batch, added to the archive as `manifest.json`.
+#[derive(Serialize)]
+pub struct BatchManifest {
+    template_id: String,
+    version: String,
+    /// Number of entries rendered into the archive
+    succeeded: usize,
+    /// Number of entries that failed and are missing in the archive
+    failed: usize,
+    /// Entries in the order of the request
+    entries: Vec<BatchEntryResult>,
+}
+
+/// Outcome of one entry of a batch
+#[derive(Serialize)]
+pub struct BatchEntryResult {
+    /// Position of the entry in the request, counted from 0
+    index: usize,
+    /// Name of the PDF in the archive, with `.pdf` added if it was missing. Failed entries
+    /// keep their name, so no later entry can use it.
+    filename: String,
+    success: bool,
+    /// Warnings of a successful entry
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warnings: Option<Vec<Diagnostic>>,
+    /// HTTP status that a request for only this entry would have failed with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status_code: Option<u16>,
+    /// Error response of a failed entry, the same as for a request for only this entry.
+    /// Compilation failures include their diagnostics.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<Value>,
+}
+
+impl BatchArchive {
+    pub fn new() -> Self {
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+
+        BatchArchive {
+            zip: ZipWriter::new_stream(SharedBuffer(buffer.clone())),
+            buffer,
+        }
+    }
+
+    /// Add a file and return the bytes of the archive written since the previous file.
+    pub fn add(&mut self, name: &str, content: &[u8]) -> io::Result<Bytes> {
+        self.zip.start_file(name, file_options())?;
+        self.zip.write_all(content)?;
+        self.zip.flush()?;
+
+        Ok(drain(&self.buffer))
+    }
+
+    /// Add the manifest, finish the archive and return its remaining bytes.
+    pub fn finish(mut self, manifest: &BatchManifest) -> io::Result<Bytes> {
+        let manifest = serde_json::to_vec_pretty(manifest).map_err(io::Error::other)?;
+        self.zip.start_file(MANIFEST_NAME, file_options())?;
+        self.zip.write_all(&manifest)?;
+        let SharedBuffer(buffer) = self.zip.finish()?.into_inner();
+
+        Ok(drain(&buffer))
+    }
+}
+
+/// Connect the task that writes a batch archive to the response that streams it.
+///
+/// At most `size` bytes are buffered before the writer waits for the reader.
+pub fn batch_stream(size: usize) -> (BatchWriter, BatchReader) {
+    let (writer, reader) = tokio::io::duplex(size);
+    let failed = Arc::new(AtomicBool::new(false));
+
+    (
+        BatchWriter {
+            writer,
+            failed: failed.clone(),
+        },
+        BatchReader { reader, failed },
+    )
+}
+
+/// Writing end of a batch stream.
+pub struct BatchWriter {
+    writer: DuplexStream,
+    failed: Arc<AtomicBool>,
+}
+
+/// Reading end of a batch stream.
+///
+/// If the archive could not be written completely, reading fails instead of ending, so the
+/// response is aborted and clients cannot mistake the truncated archive for a whole one.
+pub struct BatchReader {
+    reader: DuplexStream,
+    failed: Arc<AtomicBool>,
+}
+
+impl BatchWriter {
+    /// Send bytes of the archive. Fails if the reader was dropped.
+    pub async fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(bytes).await
+    }
+
+    /// Stop the archive, reporting an error to the reader after the bytes sent so far.
+    pub fn fail(self) {
+        self.failed.store(true, Ordering::SeqCst);
+    }
+}
+
+impl AsyncRead for BatchReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buffer: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buffer.filled().len();
+        ready!(Pin::new(&mut self.reader).poll_read(context, buffer))?;
+        // Nothing was read, so the writer is gone and the flag is final
+        if buffer.filled().len() == filled && self.failed.load(Ordering::SeqCst) {
+            return Poll::Ready(Err(io::Error::other("The batch archive is incomplete")));
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Take the bytes written to a buffer so far.
+fn drain(buffer: &Mutex<Vec<u8>>) -> Bytes {
+    let mut buffer = buffer
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    Bytes::from(std::mem::take(&mut *buffer))
+}
+
+fn file_options() -> SimpleFileOptions {
+    // PDFs are compressed already and the manifest is small
+    SimpleFileOptions::default().compression_method(CompressionMethod::Stored)
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl BatchManifest {
+    pub fn new(template_id: String, version: String) -> Self {
+        BatchManifest {
+            template_id,
+            version,
+            succeeded: 0,
+            failed: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Record an entry that was added to the archive.
+    pub fn succeeded(&mut self, filename: String, warnings: Vec<Diagnostic>) {
+        self.entries.push(BatchEntryResult {
+            index: self.entries.len(),
+            filename,
+            success: true,
+            warnings: Some(warnings),
+            status_code: None,
+            error: None,
+        });
+        self.succeeded += 1;
+    }
+
+    /// Number of entries that failed.
+    pub fn failed_count(&self) -> usize {
+        self.failed
+    }
+
+    /// Record an entry that failed with the given error response.
+    pub fn failed(&mut self, filename: String, status_code: u16, error: Value) {
+        self.entries.push(BatchEntryResult {
+            index: self.entries.len(),
+            filename,
+            success: false,
+            warnings: None,
+            status_code: Some(status_code),
+            error: Some(error),
+        });
+        self.failed += 1;
+    }
+}
+
+/// Name of an entry in a batch archive. Adds the `.pdf` extension if it is missing.
+pub fn entry_filename(filename: &str) -> String {
+    let filename = filename.trim();
+    if filename.is_empty() || filename.to_lowercase().ends_with(".pdf") {
+        filename.to_owned()
+    } else {
+        format!("{filename}.pdf")
+    }
+}
+
+/// Names of the entries of a batch archive
+#[derive(Default)]
+pub struct BatchFilenames(HashSet<String>);
+
+impl BatchFilenames {
+    /// Reserve the name of an entry before it is rendered.
+    ///
+    /// Names stay reserved if their entry fails, so every entry in the manifest has a name of
+    /// its own. Fails for names that are empty, contain a path or were reserved by an earlier
+    /// entry.
+    pub fn reserve(&mut self, filename: &str) -> Result<(), String> {
+        if filename.is_empty() {
+            return Err("The filename of a batch entry must not be empty.".to_owned());
+        }
+        if filename.contains(['/', '\\']) {
+            return Err(format!(
+                "The filename '{filename}' must not contain a path."
+            ));
+        }
+        if !self.0.insert(filename.to_owned()) {
+            return Err(format!(
+                "The filename '{filename}' is used by an earlier entry of the batch."
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use serde_json::json;
+    use tokio::io::AsyncReadExt;
+    use zip::ZipArchive;
+
+    use super::*;
+
+    #[test]
+    fn writes_successful_entries_and_the_manifest() {
+        let mut archive = BatchArchive::new();
+        let mut manifest = BatchManifest::new("table".to_owned(), "0.1.0".to_owned());
+        let mut bytes = archive.add("frank.pdf", b"%PDF-frank").unwrap().to_vec();
+        manifest.succeeded("frank.pdf".to_owned(), Vec::new());
+        manifest.failed(
+            "john.pdf".to_owned(),
+            422,
+            json!({ "message": "Invalid inputs" }),
+        );
+        bytes.extend_from_slice(&archive.finish(&manifest).unwrap());
+
+        let mut zip = ZipArchive::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(
+            zip.file_names().collect::<HashSet<_>>(),
+            HashSet::from(["frank.pdf", MANIFEST_NAME])
+        );
+        let mut pdf = Vec::new();
+        zip.by_name("frank.pdf")
+            .unwrap()
+            .read_to_end(&mut pdf)
+            .unwrap();
+        assert_eq!(pdf, b"%PDF-frank");
+        let manifest: Value = serde_json::from_reader(zip.by_name(MANIFEST_NAME).unwrap()).unwrap();
+        assert_eq!(
+            manifest,
+            json!({
+                "template_id": "table",
+                "version": "0.1.0",
+                "succeeded": 1,
+                "failed": 1,
+                "entries": [
+                    { "index": 0, "filename": "frank.pdf", "success": true, "warnings": [] },
+                    {
+                        "index": 1,
+                        "filename": "john.pdf",
+                        "success": false,
+                        "status_code": 422,
+                        "error": { "message": "Invalid inputs" },
+                    },
+                ],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_failed_archives_to_the_reader() {
+        let (mut writer, mut reader) = batch_stream(64);
+        writer.write(b"PK").await.unwrap();
+        writer.fail();
+
+        let mut bytes = [0; 2];
+        reader.read_exact(&mut bytes).await.unwrap();
+        assert_eq!(&bytes, b"PK");
+        assert!(reader.read(&mut [0; 8]).await.is_err());
+
+        let (mut writer, mut reader) = batch_stream(64);
+        writer.write(b"PK").await.unwrap();
+        drop(writer);
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await.unwrap();
+        assert_eq!(bytes, b"PK");
+    }
+
+    #[test]
+    fn adds_the_pdf_extension() {
+        assert_eq!(entry_filename("frank"), "frank.pdf");
+        assert_eq!(entry_filename(" frank.PDF "), "frank.PDF");
+        assert_eq!(entry_filename("frank.txt"), "frank.txt.pdf");
+        assert_eq!(entry_filename("  "), "");
+    }
+
+    #[test]
+    fn rejects_invalid_names() {
+        let mut filenames = BatchFilenames::default();
+        assert!(filenames.reserve("").is_err());
+        assert!(filenames.reserve("../frank.pdf").is_err());
+        assert!(filenames.reserve("a\\frank.pdf").is_err());
+    }
+
+    #[test]
+    fn rejects_names_of_earlier_entries() {
+        let mut filenames = BatchFilenames::default();
+        assert_eq!(filenames.reserve(&entry_filename("frank")), Ok(()));
+        assert_eq!(
+            filenames.reserve(&entry_filename("frank.pdf")),
+            Err("The filename 'frank.pdf' is used by an earlier entry of the batch.".to_owned())
+        );
+        assert_eq!(filenames.reserve(&entry_filename("john")), Ok(()));
+    }
+}
@@ -0,0 +1,409 @@
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    io::{Cursor, Read},
+    panic,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::anyhow;
+use dashmap::DashMap;
+use oicana::Template;
+use oicana_files::packed::PackedTemplate;
+use oicana_template::manifest::TemplateManifest;
+use oicana_world::diagnostics::DiagnosticColor;
+use semver::Version;
+use tokio::sync::OnceCell;
+use tracing::{error, info};
+use typst::utils::hash128;
+
+use crate::{
+    config::PoolConfig,
+    pool::{PoolStats, TemplatePool},
+};
+
+/// Identifies one version of a template.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TemplateKey {
+    pub id: String,
+    pub version: Version,
+}
+
+impl fmt::Display for TemplateKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} v{}", self.id, self.version)
+    }
+}
+
+/// Outcome of the last attempt to prepare a packed template.
+#[derive(Clone)]
+pub enum TemplateStatus {
+    /// The template is ready to compile.
+    Ready,
+    /// The template could not be prepared. Contains the reason.
+    Failed(String),
+}
+
+/// All templates known to the service and a cache of prepared templates.
+pub struct TemplateCache {
+    /// Directory containing the packed templates.
+    directory: PathBuf,
+    /// Number of instances to prepare for each template.
+    pool: PoolConfig,
+    /// Versions of each template that are available on disk.
+    versions: DashMap<String, BTreeMap<Version, TemplateStatus>>,
+    /// Prepared templates by their ID and version.
+    ///
+    /// Each entry is filled once by the first request that needs the template.
+    templates: DashMap<TemplateKey, Arc<OnceCell<CachedTemplate>>>,
+    /// Hashes of the packed templates that were uploaded to the service.
+    ///
+    /// Uploads are registered right away, so their files are not reloaded when the watcher
+    /// notices them.
+    uploads: DashMap<TemplateKey, u128>,
+}
+
+/// Prepared instances of a template.
+///
+/// Compiling needs exclusive access to an instance. Requests check out an instance from
+/// the pool, so requests for different templates never wait for each other.
+pub type CachedTemplate = Arc<TemplatePool>;
+
+impl TemplateCache {
+    /// Load and cache all packed templates in the given directory.
+    /// This method expects templates to compile in development mode without extra inputs.
+    pub fn warmed_up(directory: PathBuf, pool: PoolConfig) -> Self {
+        let cache = TemplateCache {
+            directory,
+            pool,
+            versions: DashMap::new(),
+            templates: DashMap::new(),
+            uploads: DashMap::new(),
+        };
+
+        for key in packed_templates(&cache.directory) {
+            match cache.load(&key.id, &cache.path(&key)) {
+                Ok(pool) => {
+                    info!("Warmed-up {key}.");
+                    cache.register(key, pool);
+                }
+                Err(error) => {
+                    error!("{key} failed during warm-up: {error}");
+                    cache.set_status(&key, TemplateStatus::Failed(error));
+                }
+            }
+        }
+
+        cache
+    }
+
+    /// Find the given version of a template, or its latest ready version if none is given.
+    pub fn resolve(&self, id: &str, version: Option<&Version>) -> Option<TemplateKey> {
+        let versions = self.versions.get(id)?;
+        let version = match version {
+            Some(version) => versions.get_key_value(version)?.0,
+            None => {
+                versions
+                    .iter()
+                    .rev()
+                    .find(|(_, status)| matches!(status, TemplateStatus::Ready))?
+                    .0
+            }
+        };
+
+        Some(TemplateKey {
+            id: id.to_owned(),
+            version: version.clone(),
+        })
+    }
+
+    /// Get a template from the cache and load it from disk if it is missing.
+    ///
+    /// Concurrent requests for a missing template wait for a single load instead of
+    /// starting their own.
+    pub async fn get(&self, key: &TemplateKey) -> Option<CachedTemplate> {
+        let entry = self.templates.entry(key.clone()).or_default().clone();
+
+        entry
+            .get_or_try_init(|| async {
+                let path = self.path(key);
+                let size = self.pool.size_for(&key.id);
+                let max_queue = self.pool.max_queue;
+                let loaded = tokio::task::spawn_blocking(move || {
+                    let bytes = read_template(&path)?;
+                    prepare_pool(bytes, size, max_queue).map_err(|error| format!("{error:#}"))
+                })
+                .await
+                .unwrap_or_else(|error| Err(format!("Loading panicked: {error}")));
+                match loaded {
+                    Ok(pool) => {
+                        info!("Loaded {key} into the cache.");
+                        self.set_status(key, TemplateStatus::Ready);
+                        Ok(Arc::new(pool))
+                    }
+                    Err(error) => {
+                        error!("Failed to load {key}: {error}");
+                        self.set_status(key, TemplateStatus::Failed(error));
+                        Err(())
+                    }
+                }
+            })
+            .await
+            .ok()
+            .cloned()
+    }
+
+    /// Remove cached versions of a template. They stay known and are reloaded on next use.
+    ///
+    /// Without a version, all cached versions of the template are removed.
+    /// Returns `false` if nothing was cached.
+    pub fn remove(&self, id: &str, version: Option<&Version>) -> bool {
+        let mut removed = false;
+        self.templates.retain(|key, entry| {
+            let keep = key.id != id || version.is_some_and(|version| key.version != *version);
+            removed |= !keep && entry.initialized();
+            keep
+        });
+
+        removed
+    }
+
+    /// Make prepared instances of a template available under the given key.
+    ///
+    /// A previously cached template with the same key is replaced. Requests that are
+    /// already using the previous template finish with it.
+    pub fn register(&self, key: TemplateKey, pool: TemplatePool) {
+        self.set_status(&key, TemplateStatus::Ready);
+        let entry = OnceCell::new_with(Some(Arc::new(pool)));
+        self.templates.insert(key, Arc::new(entry));
+    }
+
+    /// Register an uploaded template that was stored in the templates directory.
+    ///
+    /// `bytes` is the stored zip file. Reloading the file is skipped as long as it is unchanged.
+    pub fn register_upload(&self, key: TemplateKey, pool: TemplatePool, bytes: &[u8]) {
+        self.uploads.insert(key.clone(), hash128(bytes));
+        self.register(key, pool);
+    }
+
+    /// Prepare the configured number of instances of a template from its zip file.
+    pub fn prepare(&self, id: &str, bytes: Vec<u8>) -> anyhow::Result<TemplatePool> {
+        prepare_pool(bytes, self.pool.size_for(id), self.pool.max_queue)
+    }
+
+    /// Usage statistics of the cached versions of a template in ascending order.
+    pub fn pool_stats(&self, id: &str) -> Vec<(Version, PoolStats)> {
+        let mut stats: Vec<_> = self
+            .templates
+            .iter()
+            .filter(|entry| entry.key().id == id)
+            .filter_map(|entry| Some((entry.key().version.clone(), entry.value().get()?.stats())))
+            .collect();
+        stats.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        stats
+    }
+
+    /// Reload a packed template after its file changed on disk.
+    ///
+    /// If the changed template fails to load, the previously cached version stays in use.
+    /// Templates whose file was removed are forgotten.
+    pub fn reload(&self, path: &Path) {
+        let Some(key) = path
+            .file_name()
+            .and_then(|file_name| parse_template_file_name(&file_name.to_string_lossy()))
+        else {
+            return;
+        };
+
+        if !path.exists() {
+            self.templates.remove(&key);
+            self.uploads.remove(&key);
+            self.versions.remove_if_mut(&key.id, |_, versions| {
+                versions.remove(&key.version);
+                versions.is_empty()
+            });
+            info!("{key} was removed from disk.");
+            return;
+        }
+
+        let loaded = read_template(path).and_then(|bytes| {
+            if self
+                .uploads
+                .get(&key)
+                .is_some_and(|upload| *upload == hash128(&bytes))
+            {
+                return Ok(None);
+            }
+            self.uploads.remove(&key);
+            self.prepare(&key.id, bytes)
+                .map(Some)
+                .map_err(|error| format!("{error:#}"))
+        });
+        match loaded {
+            Ok(None) => info!("{key} was uploaded and is registered already."),
+            Ok(Some(pool)) => {
+                info!("Reloaded {key}.");
+                self.register(key, pool);
+            }
+            Err(error) if self.is_cached(&key) => {
+                error!("Failed to reload {key}, keeping the previous version: {error}");
+            }
+            Err(error) => {
+                error!("Failed to load {key}: {error}");
+                self.set_status(&key, TemplateStatus::Failed(error));
+            }
+        }
+    }
+
+    /// All known templates and the status of each of their versions.
+    ///
+    /// Templates are ordered by ID and their versions in ascending order.
+    pub fn list(&self) -> Vec<(String, Vec<(Version, TemplateStatus)>)> {
+        let mut templates: Vec<_> = self
+            .versions
+            .iter()
+            .map(|entry| {
+                let versions = entry
+                    .value()
+                    .iter()
+                    .map(|(version, status)| (version.clone(), status.clone()))
+                    .collect();
+                (entry.key().clone(), versions)
+            })
+            .collect();
+        templates.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        templates
+    }
+
+    /// All known versions of a template in ascending order.
+    pub fn versions(&self, id: &str) -> Option<Vec<Version>> {
+        self.versions
+            .get(id)
+            .map(|versions| versions.keys().cloned().collect())
+    }
+
+    /// Path of the packed template with the given key.
+    pub fn path(&self, key: &TemplateKey) -> PathBuf {
+        self.directory
+            .join(format!("{}-{}.zip", key.id, key.version))
+    }
+
+    /// Read a packed template from disk and prepare its instances.
+    ///
+    /// The error describes why the template could not be loaded.
+    fn load(&self, id: &str, path: &Path) -> Result<TemplatePool, String> {
+        self.prepare(id, read_template(path)?)
+            .map_err(|error| format!("{error:#}"))
+    }
+
+    fn is_cached(&self, key: &TemplateKey) -> bool {
+        self.templates
+            .get(key)
+            .is_some_and(|entry| entry.initialized())
+    }
+
+    fn set_status(&self, key: &TemplateKey, status: TemplateStatus) {
+        self.versions
+            .entry(key.id.clone())
+            .or_default()
+            .insert(key.version.clone(), status);
+    }
+}
+
+/// Prepare a packed template from the bytes of its zip file.
+pub fn init_template(bytes: Vec<u8>) -> anyhow::Result<Template<PackedTemplate>> {
+    // Reading an archive that is not a zip file panics instead of returning an error
+    let mut template = panic::catch_unwind(|| Template::init(Cursor::new(bytes)))
+        .map_err(|_| anyhow!("The file is not a zip archive"))??;
+    template.set_diagnostic_color(DiagnosticColor::None);
+
+    Ok(template)
+}
+
+/// Read the manifest of a packed template without preparing an instance.
+pub fn read_manifest(bytes: &[u8]) -> anyhow::Result<TemplateManifest> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes))
+        .map_err(|error| anyhow!("The file is not a zip archive: {error}"))?;
+    let mut manifest = String::new();
+    archive
+        .by_name("typst.toml")
+        .map_err(|error| anyhow!("Failed to find the manifest file `typst.toml`: {error}"))?
+        .read_to_string(&mut manifest)?;
+
+    TemplateManifest::from_toml(&manifest)
+        .map_err(|error| anyhow!("Failed to parse the manifest file `typst.toml`: {error}"))
+}
+
+/// Prepare `size` instances of a packed template.
+fn prepare_pool(bytes: Vec<u8>, size: usize, max_queue: usize) -> anyhow::Result<TemplatePool> {
+    let instances = (0..size)
+        .map(|_| init_template(bytes.clone()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(TemplatePool::new(instances, max_queue))
+}
+
+/// Read the zip file of a packed template.
+fn read_template(path: &Path) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|error| format!("Failed to read '{}': {error}", path.display()))
+}
+
+/// Find all packed templates in the templates directory.
+fn packed_templates(directory: &Path) -> Vec<TemplateKey> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(error) => {
+            error!(
+                "Failed to read the templates directory '{}': {error:?}",
+                directory.display()
+            );
+            return Vec::new();
+        }
+    };
+
+    let mut keys: Vec<TemplateKey> = entries
+        .filter_map(|entry| parse_template_file_name(&entry.ok()?.file_name().to_string_lossy()))
+        .collect();
+    keys.sort_by(|a, b| a.id.cmp(&b.id).then_with(|| a.version.cmp(&b.version)));
+
+    keys
+}
+
+/// Parse a file name of the form `{id}-{version}.zip`.
+///
+/// Both template IDs and versions may contain dashes, so the first split that leaves a valid
+/// semantic version is used.
+fn parse_template_file_name(file_name: &str) -> Option<TemplateKey> {
+    let stem = file_name.strip_suffix(".zip")?;
+
+    stem.match_indices('-').find_map(|(index, _)| {
+        let version = Version::parse(&stem[index + 1..]).ok()?;
+        Some(TemplateKey {
+            id: stem[..index].to_owned(),
+            version,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_the_manifest_of_packed_templates() {
+        let bytes = fs::read("templates/table-0.1.0.zip").unwrap();
+        let manifest = read_manifest(&bytes).unwrap();
+        assert_eq!(manifest.package.name, "table");
+        assert_eq!(manifest.package.version.to_string(), "0.1.0");
+
+        let error = read_manifest(b"not a zip file").unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("The file is not a zip archive")
+        );
+    }
+}
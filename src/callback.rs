@@ -0,0 +1,498 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use reqwest::{
+    Client, StatusCode, Url,
+    dns::{Addrs, Name, Resolve, Resolving},
+    header,
+    redirect::Policy,
+};
+use sha2::Sha256;
+use tracing::{error, info, warn};
+
+use crate::{config::CallbackConfig, job::JobStatus};
+
+/// Header with the Unix time at which a callback was signed.
+const TIMESTAMP_HEADER: &str = "x-oicana-timestamp";
+/// Header with the signature of a callback, as `sha256={hex}`.
+const SIGNATURE_HEADER: &str = "x-oicana-signature";
+
+/// Signed notifications about finished jobs, posted to URLs that the jobs requested.
+///
+/// Receivers verify a callback by computing the HMAC-SHA256 of `{timestamp}.{body}` with the
+/// shared secret. Failed deliveries are retried with exponential backoff.
+///
+/// Callbacks only reach public addresses, unless their host is explicitly allowed. Hosts are
+/// checked when a job requests the callback and again whenever it is delivered, and redirects
+/// are not followed.
+pub struct Callbacks {
+    client: Client,
+    secret: Option<String>,
+    allowed_hosts: Arc<HashSet<String>>,
+    public_url: String,
+    attempts: u32,
+    backoff: Duration,
+}
+
+impl Callbacks {
+    pub fn new(config: &CallbackConfig) -> Self {
+        let allowed_hosts = Arc::new(
+            config
+                .allowed_hosts
+                .iter()
+                .map(|host| normalize_host(host))
+                .collect::<HashSet<_>>(),
+        );
+        let client = Client::builder()
+            .timeout(Duration::from_secs(config.timeout))
+            .redirect(Policy::none())
+            .dns_resolver(Arc::new(PublicResolver {
+                allowed_hosts: allowed_hosts.clone(),
+            }))
+            .build()
+            .expect("Failed to create the HTTP client for callbacks");
+
+        Callbacks {
+            client,
+            secret: config.secret.clone(),
+            allowed_hosts,
+            public_url: config.public_url.trim_end_matches('/').to_owned(),
+            attempts: config.attempts.max(1),
+            backoff: Duration::from_secs(config.backoff),
+        }
+    }
+
+    /// Check a callback URL requested by a job.
+    ///
+    /// The host of the URL needs to be allowed or resolve to public addresses only.
+    pub async fn check_url(&self, url: &str) -> Result<Url, String> {
+        if self.secret.is_none() {
+            return Err(
+                "Callbacks are disabled, because no secret to sign them is configured.".to_owned(),
+            );
+        }
+        let parsed =
+            Url::parse(url).map_err(|error| format!("'{url}' is not a valid URL: {error}."))?;
+
+        if let scheme @ ("http" | "https") = parsed.scheme() {
+            let host = parsed
+                .host_str()
+                .ok_or_else(|| format!("The {scheme} URL '{url}' has no host."))?;
+            let host = normalize_host(host);
+            if self.allowed_hosts.contains(&host) {
+                return Ok(parsed);
+            }
+            match host.parse::<IpAddr>() {
+                Ok(address) => check_address(&host, address)?,
+                Err(_) => {
+                    resolve_public(&host).await?;
+                }
+            }
+            Ok(parsed)
+        } else {
+            Err(format!(
+                "Callback URLs need to use http or https, not '{}'.",
+                parsed.scheme()
+            ))
+        }
+    }
+
+    /// Post the status of a finished job to the given URL.
+    ///
+    /// Attempts fail on connection errors and on responses without a success status,
+    /// including redirects. Client errors other than 408 and 429 are not retried.
+    pub async fn notify(&self, url: Url, status: JobStatus) {
+        let Some(secret) = &self.secret else {
+            return;
+        };
+        let job_id = status.id();
+        let body = serde_json::to_vec(&status.linked_from(&self.public_url))
+            .expect("The job status is valid JSON");
+
+        let mut backoff = self.backoff;
+        for attempt in 1..=self.attempts {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+                .to_string();
+            let response = self
+                .client
+                .post(url.clone())
+                .header(header::CONTENT_TYPE, "application/json")
+                .header(TIMESTAMP_HEADER, &timestamp)
+                .header(
+                    SIGNATURE_HEADER,
+                    format!("sha256={}", sign(secret, &timestamp, &body)),
+                )
+                .body(body.clone())
+                .send()
+                .await;
+
+            let retry = match response {
+                Ok(response) if response.status().is_success() => {
+                    info!(%job_id, "Delivered the callback of job {job_id} to {url}");
+                    return;
+                }
+                Ok(response) => {
+                    let status = response.status();
+                    warn!(%job_id, %status, "Attempt {attempt} to deliver the callback of job {job_id} to {url} failed with {status}");
+                    retryable(status)
+                }
+                Err(error) => {
+                    warn!(%job_id, %error, "Attempt {attempt} to deliver the callback of job {job_id} to {url} failed: {error}");
+                    true
+                }
+            };
+            if !retry || attempt == self.attempts {
+                break;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+
+        error!(%job_id, "Gave up delivering the callback of job {job_id} to {url}");
+    }
+}
+
+/// Resolves the hosts of callbacks, refusing hosts with addresses that are not public unless
+/// they are allowed. IP addresses in URLs are not resolved, they are checked by
+/// [`Callbacks::check_url`].
+struct PublicResolver {
+    allowed_hosts: Arc<HashSet<String>>,
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = normalize_host(name.as_str());
+        let allowed = self.allowed_hosts.contains(&host);
+
+        Box::pin(async move {
+            let addresses = if allowed {
+                resolve(&host).await?
+            } else {
+                resolve_public(&host).await?
+            };
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+async fn resolve(host: &str) -> Result<Vec<SocketAddr>, String> {
+    let addresses = tokio::net::lookup_host((host, 0))
+        .await
+        .map_err(|error| format!("Failed to resolve '{host}': {error}."))?
+        .collect::<Vec<_>>();
+    if addresses.is_empty() {
+        return Err(format!("'{host}' does not resolve to any address."));
+    }
+
+    Ok(addresses)
+}
+
+/// Resolve a host that needs to have public addresses only.
+async fn resolve_public(host: &str) -> Result<Vec<SocketAddr>, String> {
+    let addresses = resolve(host).await?;
+    for address in &addresses {
+        check_address(host, address.ip())?;
+    }
+
+    Ok(addresses)
+}
+
+fn check_address(host: &str, address: IpAddr) -> Result<(), String> {
+    if is_public(address) {
+        Ok(())
+    } else {
+        Err(format!(
+            "'{host}' is the loopback, private or otherwise non-public address {address}. Allow the host in the callback configuration to reach it."
+        ))
+    }
+}
+
+fn is_public(address: IpAddr) -> bool {
+    match address.to_canonical() {
+        IpAddr::V4(address) => {
+            let [first, second, ..] = address.octets();
+            // Shared address space of carrier-grade NAT, 100.64.0.0/10
+            let shared = first == 100 && second & 0b1100_0000 == 64;
+            !(address.is_loopback()
+                || address.is_private()
+                || address.is_link_local()
+                || address.is_unspecified()
+                || address.is_broadcast()
+                || address.is_multicast()
+                || shared)
+        }
+        IpAddr::V6(address) => {
+            !(address.is_loopback()
+                || address.is_unspecified()
+                || address.is_multicast()
+                || address.is_unique_local()
+                || address.is_unicast_link_local())
+        }
+    }
+}
+
+/// Hosts are compared in lowercase and IPv6 addresses without brackets.
+fn normalize_host(host: &str) -> String {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_lowercase()
+}
+
+fn retryable(status: StatusCode) -> bool {
+    status.is_server_error()
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Hex encoded HMAC-SHA256 of `{timestamp}.{body}`.
+fn sign(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex, time::Instant};
+
+    use axum::{
+        Router, body::Bytes, extract::State, http::HeaderMap, response::Redirect, routing::post,
+    };
+    use serde_json::Value;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        config::JobConfig,
+        job::{JobFailure, JobStore},
+    };
+
+    const SECRET: &str = "secret";
+
+    struct Received {
+        at: Instant,
+        headers: HeaderMap,
+        body: Bytes,
+    }
+
+    #[derive(Clone)]
+    struct Receiver {
+        /// Status of each response, the last one repeats
+        statuses: Arc<Vec<StatusCode>>,
+        received: Arc<Mutex<Vec<Received>>>,
+    }
+
+    async fn receive(
+        State(receiver): State<Receiver>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> StatusCode {
+        let mut received = receiver.received.lock().unwrap();
+        received.push(Received {
+            at: Instant::now(),
+            headers,
+            body,
+        });
+        let index = (received.len() - 1).min(receiver.statuses.len() - 1);
+        receiver.statuses[index]
+    }
+
+    /// Start a receiver on a free local port that answers callbacks with the given statuses.
+    async fn start_receiver(statuses: Vec<StatusCode>) -> (u16, Arc<Mutex<Vec<Received>>>) {
+        let receiver = Receiver {
+            statuses: Arc::new(statuses),
+            received: Arc::default(),
+        };
+        let received = receiver.received.clone();
+        let app = Router::new()
+            .route("/callback", post(receive))
+            .route(
+                "/moved",
+                post(|| async { Redirect::temporary("/callback") }),
+            )
+            .with_state(receiver);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        (port, received)
+    }
+
+    fn callbacks(attempts: u32, allowed_hosts: &[&str]) -> Callbacks {
+        Callbacks::new(&CallbackConfig {
+            secret: Some(SECRET.to_owned()),
+            attempts,
+            allowed_hosts: allowed_hosts.iter().map(|host| host.to_string()).collect(),
+            ..CallbackConfig::default()
+        })
+    }
+
+    fn status() -> JobStatus {
+        JobStore::new(&JobConfig::default())
+            .submit("table".to_owned(), "0.1.0".to_owned(), None, async {
+                Err(JobFailure {
+                    status: StatusCode::BAD_REQUEST,
+                    body: Value::Null,
+                })
+            })
+            .unwrap()
+    }
+
+    async fn notify(callbacks: &Callbacks, url: &str, status: JobStatus) {
+        let url = callbacks.check_url(url).await.unwrap();
+        callbacks.notify(url, status).await;
+    }
+
+    #[tokio::test]
+    async fn signs_callbacks_with_the_hmac_of_timestamp_and_body() {
+        let (port, received) = start_receiver(vec![StatusCode::OK]).await;
+        let status = status();
+        let job_id = status.id();
+
+        notify(
+            &callbacks(1, &["127.0.0.1"]),
+            &format!("http://127.0.0.1:{port}/callback"),
+            status,
+        )
+        .await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        let Received { headers, body, .. } = &received[0];
+        let timestamp = headers[TIMESTAMP_HEADER].to_str().unwrap();
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(format!("{timestamp}.").as_bytes());
+        mac.update(body);
+        let expected: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        assert_eq!(headers[SIGNATURE_HEADER], format!("sha256={expected}"));
+
+        let body: Value = serde_json::from_slice(body).unwrap();
+        assert_eq!(body["id"], job_id.to_string());
+    }
+
+    #[tokio::test]
+    async fn retries_server_errors_after_the_backoff() {
+        let (port, received) =
+            start_receiver(vec![StatusCode::INTERNAL_SERVER_ERROR, StatusCode::OK]).await;
+        let callbacks = callbacks(5, &["127.0.0.1"]);
+
+        notify(
+            &callbacks,
+            &format!("http://127.0.0.1:{port}/callback"),
+            status(),
+        )
+        .await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        let wait = received[1].at - received[0].at;
+        assert!(wait >= callbacks.backoff, "retried after {wait:?}");
+        assert!(wait < callbacks.backoff * 2, "retried after {wait:?}");
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_client_errors() {
+        let (port, received) = start_receiver(vec![StatusCode::NOT_FOUND]).await;
+
+        notify(
+            &callbacks(5, &["127.0.0.1"]),
+            &format!("http://127.0.0.1:{port}/callback"),
+            status(),
+        )
+        .await;
+
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn does_not_follow_redirects() {
+        let (port, received) = start_receiver(vec![StatusCode::OK]).await;
+
+        notify(
+            &callbacks(1, &["127.0.0.1"]),
+            &format!("http://127.0.0.1:{port}/moved"),
+            status(),
+        )
+        .await;
+
+        assert!(received.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_non_public_addresses() {
+        let callbacks = callbacks(1, &[]);
+
+        for url in [
+            "http://127.0.0.1/callback",
+            "http://localhost:8080/callback",
+            "http://10.0.0.1/callback",
+            "http://192.168.1.1/callback",
+            "http://169.254.169.254/latest/meta-data",
+            "http://100.64.0.1/callback",
+            "http://0.0.0.0/callback",
+            "http://[::1]/callback",
+            "http://[fd00::1]/callback",
+            "http://[::ffff:127.0.0.1]/callback",
+        ] {
+            let error = callbacks.check_url(url).await.unwrap_err();
+            assert!(error.contains("non-public address"), "{url}: {error}");
+        }
+        assert!(
+            callbacks
+                .check_url("https://93.184.215.14/callback")
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn allows_configured_hosts() {
+        let callbacks = callbacks(1, &["LOCALHOST", "[::1]", "10.0.0.1"]);
+
+        for url in [
+            "http://localhost:8080/callback",
+            "http://[::1]/callback",
+            "http://10.0.0.1/callback",
+        ] {
+            assert!(callbacks.check_url(url).await.is_ok(), "{url}");
+        }
+        assert!(
+            callbacks
+                .check_url("http://10.0.0.2/callback")
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_non_public_hosts_when_delivering() {
+        let (port, received) = start_receiver(vec![StatusCode::OK]).await;
+        let url = Url::parse(&format!("http://localhost:{port}/callback")).unwrap();
+
+        // The URL passed the check before, but its host resolves to a loopback address now
+        callbacks(1, &["127.0.0.1"]).notify(url, status()).await;
+
+        assert!(received.lock().unwrap().is_empty());
+    }
+}
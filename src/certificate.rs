@@ -7,11 +7,10 @@ use axum::{
     http::{StatusCode, header},
     response::{IntoResponse, Response},
 };
-use dashmap::DashMap;
-use oicana::Template;
 use oicana_export::pdf::export_merged_pdf;
-use oicana_files::packed::PackedTemplate;
-use oicana_input::{CompilationConfig, TemplateInputs, input::json::JsonInput as OicanaJsonInput};
+use oicana_input::{
+    CompilationConfig, CompilationMode, TemplateInputs, input::json::JsonInput as OicanaJsonInput,
+};
 use oicana_world::TemplateCompilationFailure;
 use serde::{Deserialize, Serialize};
 use tracing::error;
@@ -19,15 +18,34 @@ use utoipa::ToSchema;
 use utoipa_axum::router::OpenApiRouter;
 use utoipa_axum::routes;
 
-type TemplateCache = Arc<DashMap<String, Template<PackedTemplate>>>;
+use crate::{
+    cache::TemplateCache,
+    diagnostics::{CompilationFailureResponse, failure_response},
+    pool::PoolExhausted,
+    warnings::WarningStore,
+    worker::CompilePool,
+};
 
 #[derive(Clone)]
 struct AppState {
-    template_cache: TemplateCache,
+    template_cache: Arc<TemplateCache>,
+    compile_pool: Arc<CompilePool>,
+    warnings: Arc<WarningStore>,
+    mode: CompilationMode,
 }
 
-pub fn router(template_cache: TemplateCache) -> OpenApiRouter {
-    let state = AppState { template_cache };
+pub fn router(
+    template_cache: Arc<TemplateCache>,
+    compile_pool: Arc<CompilePool>,
+    warnings: Arc<WarningStore>,
+    mode: CompilationMode,
+) -> OpenApiRouter {
+    let state = AppState {
+        template_cache,
+        compile_pool,
+        warnings,
+        mode,
+    };
 
     OpenApiRouter::new()
         .routes(routes!(create_certificate))
@@ -39,6 +57,9 @@ enum CertificateError {
     SerializationFailure(String),
     CompilationFailure(TemplateCompilationFailure),
     ExportFailure(String),
+    Aborted,
+    Cancelled,
+    Busy,
 }
 
 impl IntoResponse for CertificateError {
@@ -75,17 +96,11 @@ impl IntoResponse for CertificateError {
                         error!("Certificate template failed to compile: {}", error.error)
                     }
                 }
-                (
+                return failure_response(
                     StatusCode::BAD_REQUEST,
-                    format!(
-                        "Failed to compile certificate: {}{}",
-                        error.error,
-                        error
-                            .warnings
-                            .map(|warning| format!("\n\n{warning}"))
-                            .unwrap_or(String::new())
-                    ),
-                )
+                    "Failed to compile certificate".to_string(),
+                    error,
+                );
             }
             CertificateError::ExportFailure(error) => {
                 error!(%error, "Certificate failed to export");
@@ -94,6 +109,27 @@ impl IntoResponse for CertificateError {
                     format!("Failed to export certificate: {error}"),
                 )
             }
+            CertificateError::Aborted => {
+                error!("Certificate compilation was aborted");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Certificate compilation was aborted!".to_string(),
+                )
+            }
+            CertificateError::Cancelled => {
+                error!("Certificate compilation was cancelled");
+                (
+                    StatusCode::REQUEST_TIMEOUT,
+                    "Certificate compilation was cancelled!".to_string(),
+                )
+            }
+            CertificateError::Busy => {
+                error!("Too many certificate requests are waiting");
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Too many certificate requests are waiting, try again later.".to_string(),
+                )
+            }
         };
 
         (status, Json(ErrorResponse { message })).into_response()
@@ -116,7 +152,11 @@ struct CreateCertificate {
     request_body(content = CreateCertificate, description = "Certificate details", content_type = "application/json"),
     description = "Create a certificate",
     responses(
-        (status = OK, description = "The compiled PDF certificate", content_type = "application/pdf")
+        (status = OK, description = "The compiled PDF certificate. If the compilation produced warnings, the `x-compilation-warnings` header holds their number and the `link` header points to them.", content_type = "application/pdf"),
+        (status = BAD_REQUEST, description = "The certificate failed to compile. Request `text/plain` for the rendered diagnostics.", content(
+            (CompilationFailureResponse = "application/json"),
+            (String = "text/plain")
+        ))
     )
 )]
 #[axum::debug_handler]
@@ -125,12 +165,15 @@ async fn create_certificate(
     Json(request): Json<CreateCertificate>,
 ) -> Result<impl IntoResponse, CertificateError> {
     let template_id = "certificate";
-    let Some(mut template) = state.template_cache.get_mut(template_id) else {
+    let Some(key) = state.template_cache.resolve(template_id, None) else {
+        return Err(CertificateError::TemplateNotFound);
+    };
+    let Some(pool) = state.template_cache.get(&key).await else {
         return Err(CertificateError::TemplateNotFound);
     };
 
     let mut inputs = TemplateInputs::new();
-    inputs.with_config(CompilationConfig::development());
+    inputs.with_config(CompilationConfig::new(state.mode));
 
     // Serialize the typed input to JSON and pass it with the key "certificate"
     // This matches the template's expected input key
@@ -143,17 +186,33 @@ async fn create_certificate(
         json_value.to_string(),
     ));
 
-    let compilation_result = template
-        .compile(inputs)
-        .map_err(CertificateError::CompilationFailure)?;
+    let mut template = pool
+        .checkout()
+        .await
+        .map_err(|PoolExhausted| CertificateError::Busy)?;
+    let (pdf, warnings) = state
+        .compile_pool
+        .run(move |cancellation| {
+            let compilation_result = template
+                .compile(inputs)
+                .map_err(CertificateError::CompilationFailure)?;
+            if cancellation.is_cancelled() {
+                return Err(CertificateError::Cancelled);
+            }
 
-    let pdf = export_merged_pdf(
-        &compilation_result.document,
-        &*template,
-        &template.manifest().tool.oicana.export.pdf.standards,
-    )
-    .map_err(CertificateError::ExportFailure)?;
+            let pdf = export_merged_pdf(
+                &compilation_result.document,
+                &*template,
+                &template.manifest().tool.oicana.export.pdf.standards,
+            )
+            .map_err(CertificateError::ExportFailure)?;
+
+            Ok((pdf, compilation_result.warnings))
+        })
+        .await
+        .map_err(|_| CertificateError::Aborted)??;
 
+    let warnings = state.warnings.record(template_id, warnings);
     let body = Body::from(pdf);
 
     let headers = [
@@ -164,5 +223,5 @@ async fn create_certificate(
         ),
     ];
 
-    Ok((headers, body))
+    Ok((headers, warnings, body))
 }
@@ -0,0 +1,206 @@
+use std::{
+    collections::HashMap, env, fs, num::NonZeroUsize, path::PathBuf, thread, time::Duration,
+};
+
+use anyhow::Context;
+use oicana_input::CompilationMode;
+use serde::Deserialize;
+use tracing::info;
+
+/// Environment variable with the path to the configuration file.
+const CONFIG_PATH_VARIABLE: &str = "OICANA_CONFIG";
+/// Configuration file used if no path is given in the environment.
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// Configuration of the service.
+///
+/// All values are optional in the configuration file and fall back to their defaults.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Directory containing packed templates named `{id}-{version}.zip`.
+    pub templates_directory: PathBuf,
+    /// Reload packed templates when their files in the templates directory change.
+    pub watch_templates: bool,
+    /// Mode of compilations that do not request a mode.
+    pub compilation_mode: CompilationMode,
+    /// Number of threads that compile templates. Defaults to the number of CPU cores.
+    pub compile_threads: usize,
+    /// Number of compilations whose warnings are kept to be fetched after the response.
+    pub retained_warnings: usize,
+    /// Instances of each template that are prepared to compile in parallel.
+    pub pool: PoolConfig,
+    /// Time limits for requests.
+    pub timeouts: TimeoutConfig,
+    /// Render jobs that run in the background.
+    pub jobs: JobConfig,
+}
+
+/// Configuration of the template instance pools.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct PoolConfig {
+    /// Number of instances per template version.
+    pub size: usize,
+    /// Requests that may wait for a free instance before further requests are rejected.
+    pub max_queue: usize,
+    /// Number of instances for specific template IDs, overriding `size`.
+    pub templates: HashMap<String, usize>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            size: 1,
+            max_queue: 64,
+            templates: HashMap::new(),
+        }
+    }
+}
+
+impl PoolConfig {
+    /// Number of instances to prepare for the given template. Always at least one.
+    pub fn size_for(&self, id: &str) -> usize {
+        self.templates.get(id).copied().unwrap_or(self.size).max(1)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            templates_directory: PathBuf::from("templates"),
+            watch_templates: true,
+            compilation_mode: CompilationMode::Production,
+            compile_threads: thread::available_parallelism().map_or(4, NonZeroUsize::get),
+            retained_warnings: 1000,
+            pool: PoolConfig::default(),
+            timeouts: TimeoutConfig::default(),
+            jobs: JobConfig::default(),
+        }
+    }
+}
+
+/// Time limits for requests in milliseconds.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    /// Limit for requests without a more specific limit.
+    pub default: u64,
+    /// Limits for routes, keyed by method and route path like `POST /blobs`.
+    pub routes: HashMap<String, u64>,
+    /// Limits for requests to templates, keyed by template ID. They take precedence over
+    /// route limits.
+    pub templates: HashMap<String, u64>,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig {
+            default: 1000,
+            routes: HashMap::new(),
+            templates: HashMap::new(),
+        }
+    }
+}
+
+impl TimeoutConfig {
+    /// Time limit for a request to the given route and template.
+    pub fn limit(&self, route: Option<&str>, template_id: Option<&str>) -> Duration {
+        let template_limit = template_id.and_then(|id| self.templates.get(id));
+        let route_limit = route.and_then(|route| self.routes.get(route));
+
+        Duration::from_millis(*template_limit.or(route_limit).unwrap_or(&self.default))
+    }
+}
+
+/// Configuration of render jobs.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct JobConfig {
+    /// Number of jobs that run at the same time.
+    pub workers: usize,
+    /// Jobs that may wait for a worker before further jobs are rejected.
+    pub max_queue: usize,
+    /// Seconds that finished jobs and their results are kept.
+    pub retention: u64,
+    /// Time limit of a job in seconds, including the time it waits for a template instance.
+    pub timeout: u64,
+    /// Notifications of finished jobs.
+    pub callback: CallbackConfig,
+}
+
+impl Default for JobConfig {
+    fn default() -> Self {
+        JobConfig {
+            workers: 2,
+            max_queue: 100,
+            retention: 3600,
+            timeout: 600,
+            callback: CallbackConfig::default(),
+        }
+    }
+}
+
+/// Configuration of the callbacks that report finished jobs.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct CallbackConfig {
+    /// Secret to sign callbacks with. Jobs cannot request callbacks without it.
+    pub secret: Option<String>,
+    /// URL under which receivers of callbacks reach the service, to link the results.
+    pub public_url: String,
+    /// Attempts to deliver a callback before giving up.
+    pub attempts: u32,
+    /// Seconds to wait before the first retry. The wait doubles with every retry.
+    pub backoff: u64,
+    /// Time limit of one attempt in seconds.
+    pub timeout: u64,
+    /// Hosts that callbacks may reach although they are or resolve to loopback, private or
+    /// otherwise non-public addresses.
+    pub allowed_hosts: Vec<String>,
+}
+
+impl Default for CallbackConfig {
+    fn default() -> Self {
+        CallbackConfig {
+            secret: None,
+            public_url: "http://127.0.0.1:3000".to_owned(),
+            attempts: 5,
+            backoff: 1,
+            timeout: 10,
+            allowed_hosts: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration file given by `OICANA_CONFIG` or `config.toml`.
+    ///
+    /// A missing default configuration file results in the default configuration.
+    pub fn load() -> anyhow::Result<Self> {
+        let (path, required) = match env::var(CONFIG_PATH_VARIABLE) {
+            Ok(path) => (PathBuf::from(path), true),
+            Err(_) => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+        };
+
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(error) if !required && error.kind() == std::io::ErrorKind::NotFound => {
+                info!(
+                    "No configuration file found at {}, using defaults.",
+                    path.display()
+                );
+                return Ok(Config::default());
+            }
+            Err(error) => {
+                return Err(error).with_context(|| {
+                    format!("Failed to read configuration file {}", path.display())
+                });
+            }
+        };
+        info!("Loaded configuration from {}.", path.display());
+
+        toml::from_str(&content)
+            .with_context(|| format!("Invalid configuration file {}", path.display()))
+    }
+}
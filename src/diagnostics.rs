@@ -0,0 +1,567 @@
+use axum::{
+    Json,
+    extract::Request,
+    http::{HeaderValue, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use oicana_world::TemplateCompilationFailure;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::render::media_ranges;
+
+/// Response for a template that failed to compile
+#[derive(ToSchema, Serialize)]
+pub struct CompilationFailureResponse {
+    /// Summary of the failure
+    #[schema(example = "Template 'table' failed to compile with given inputs")]
+    message: String,
+    /// Errors and warnings of the compilation, errors first
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// An error or warning reported while compiling a template
+#[derive(ToSchema, Serialize, Clone)]
+pub struct Diagnostic {
+    severity: Severity,
+    #[schema(example = "dictionary does not contain key \"description\"")]
+    message: String,
+    /// Path of the file inside the template. Files of packages are prefixed with the package.
+    #[schema(example = "/main.typ")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    span: Option<Span>,
+    /// Suggestions how to fix the problem
+    hints: Vec<String>,
+    /// Calls that led to the problem, innermost first
+    trace: Vec<TracePoint>,
+}
+
+/// Severity of a diagnostic
+#[derive(ToSchema, Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A call that led to a diagnostic
+#[derive(ToSchema, Serialize, Clone)]
+pub struct TracePoint {
+    #[schema(example = "error occurred in this call of function `table`")]
+    message: String,
+    #[schema(example = "/main.typ")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    span: Option<Span>,
+}
+
+/// Source range of a diagnostic. The end is exclusive.
+#[derive(ToSchema, Serialize, Clone, Copy)]
+pub struct Span {
+    start: Position,
+    end: Position,
+}
+
+/// Position in a source file. Lines and columns start at 1.
+#[derive(ToSchema, Serialize, Clone, Copy)]
+pub struct Position {
+    #[schema(example = 8)]
+    line: usize,
+    #[schema(example = 13)]
+    column: usize,
+}
+
+impl Diagnostic {
+    pub fn is_warning(&self) -> bool {
+        matches!(self.severity, Severity::Warning)
+    }
+}
+
+/// Text rendering of the JSON body of an error response, used if the client prefers plain
+/// text.
+#[derive(Clone)]
+struct PlainTextDiagnostics(String);
+
+/// Offer the given text instead of the JSON body of a response to clients that prefer
+/// `text/plain`, see [`negotiate_diagnostics`].
+pub fn with_plain_text(mut response: Response, text: String) -> Response {
+    response.extensions_mut().insert(PlainTextDiagnostics(text));
+
+    response
+}
+
+/// Respond with the diagnostics of a failed compilation as JSON.
+///
+/// Clients that prefer `text/plain` get the diagnostics as rendered text instead,
+/// see [`negotiate_diagnostics`].
+pub fn failure_response(
+    status: StatusCode,
+    message: String,
+    failure: TemplateCompilationFailure,
+) -> Response {
+    let text = format!(
+        "{message}: {}{}",
+        failure.error,
+        failure
+            .warnings
+            .as_ref()
+            .map(|warning| format!("\n\n{warning}"))
+            .unwrap_or_default()
+    );
+    let body = CompilationFailureResponse {
+        message,
+        diagnostics: parse(&failure),
+    };
+
+    with_plain_text((status, Json(body)).into_response(), text)
+}
+
+/// Replace JSON error bodies with their text rendering if the request prefers `text/plain`.
+///
+/// Applies to compilation failures and to inputs that do not match their schemas.
+pub async fn negotiate_diagnostics(request: Request, next: Next) -> Response {
+    let plain_text = {
+        let accept: Vec<&str> = request
+            .headers()
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|accept| accept.to_str().ok())
+            .collect();
+        prefers_plain_text(&accept)
+    };
+    let mut response = next.run(request).await;
+
+    if plain_text && let Some(PlainTextDiagnostics(text)) = response.extensions_mut().remove() {
+        let status = response.status();
+        return (
+            status,
+            [(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("text/plain; charset=utf-8"),
+            )],
+            text,
+        )
+            .into_response();
+    }
+
+    response
+}
+
+/// Whether `Accept` headers prefer `text/plain` over `application/json`, by quality and then
+/// by order.
+fn prefers_plain_text(accept: &[&str]) -> bool {
+    let (ranges, _) = media_ranges(accept);
+    ranges
+        .into_iter()
+        .map(|(media_type, _)| media_type)
+        .find(|media_type| matches!(*media_type, "text/plain" | "application/json"))
+        == Some("text/plain")
+}
+
+/// Parse the diagnostics of a failed compilation.
+///
+/// Oicana only reports diagnostics as text rendered by codespan-reporting, so this
+/// reads them back from that rendering.
+pub fn parse(failure: &TemplateCompilationFailure) -> Vec<Diagnostic> {
+    let mut diagnostics = parse_rendered(&failure.error);
+    if let Some(warnings) = &failure.warnings {
+        diagnostics.extend(parse_rendered(warnings));
+    }
+
+    diagnostics
+}
+
+/// Kinds of blocks in rendered diagnostics.
+enum BlockKind {
+    Diagnostic(Severity),
+    /// A trace point of the preceding diagnostic
+    Help,
+}
+
+/// A single rendered block, starting with a line like `error: message`.
+struct Block {
+    kind: BlockKind,
+    message: String,
+    file: Option<String>,
+    span: Option<Span>,
+    notes: Vec<String>,
+}
+
+/// Parse diagnostics rendered by codespan-reporting.
+pub fn parse_rendered(text: &str) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    let mut lines = text.lines().peekable();
+
+    while let Some(line) = lines.next() {
+        let Some((kind, message)) = block_header(line) else {
+            continue;
+        };
+        let mut body = Vec::new();
+        while let Some(line) = lines.next_if(|line| block_header(line).is_none()) {
+            body.push(line);
+        }
+
+        let block = parse_block(kind, message, &body);
+        match block.kind {
+            BlockKind::Diagnostic(severity) => diagnostics.push(Diagnostic {
+                severity,
+                message: block.message,
+                file: block.file,
+                span: block.span,
+                hints: block.notes,
+                trace: Vec::new(),
+            }),
+            BlockKind::Help => {
+                if let Some(diagnostic) = diagnostics.last_mut() {
+                    diagnostic.trace.push(TracePoint {
+                        message: block.message,
+                        file: block.file,
+                        span: block.span,
+                    });
+                }
+            }
+        }
+    }
+
+    diagnostics
+}
+
+/// Parse the first line of a block like `warning: message`.
+fn block_header(line: &str) -> Option<(BlockKind, &str)> {
+    let (kind, message) = line.split_once(": ")?;
+    // Diagnostics may carry a code like `error[E001]`
+    let kind = match kind.split('[').next()? {
+        "warning" => BlockKind::Diagnostic(Severity::Warning),
+        "help" | "note" => BlockKind::Help,
+        "error" | "bug" => BlockKind::Diagnostic(Severity::Error),
+        _ => return None,
+    };
+
+    Some((kind, message))
+}
+
+/// Parse the lines following a block header.
+///
+/// ```text
+///   ┌─ /main.typ:4:1
+///   │
+/// 4 │   #table(
+///   │ ╭──^
+/// 5 │ │   columns: 2,
+/// 6 │ │ )
+///   │ ╰─^
+///   │
+///   = hint: a hint
+/// ```
+fn parse_block(kind: BlockKind, message: &str, lines: &[&str]) -> Block {
+    let mut block = Block {
+        kind,
+        message: message.to_owned(),
+        file: None,
+        span: None,
+        notes: Vec::new(),
+    };
+    let mut source_line = None;
+
+    for line in lines {
+        let trimmed = line.trim_start();
+        if let Some(location) = trimmed.strip_prefix("┌─ ") {
+            let (file, start) = parse_location(location);
+            block.file = Some(file);
+            block.span = start.map(|start| Span { start, end: start });
+        } else if let Some(note) = trimmed.strip_prefix("= ") {
+            block
+                .notes
+                .push(note.strip_prefix("hint: ").unwrap_or(note).to_owned());
+        } else if let Some((gutter, content)) = line.split_once('│')
+            && (gutter.trim().is_empty() || gutter.trim().parse::<usize>().is_ok())
+        {
+            if let Ok(number) = gutter.trim().parse::<usize>() {
+                source_line = Some(number);
+            } else if let (Some(span), Some(line)) = (block.span.as_mut(), source_line) {
+                extend_span(span, line, content);
+            }
+        } else if block.file.is_none() && !trimmed.is_empty() {
+            // Messages spanning multiple lines
+            block.message.push('\n');
+            block.message.push_str(line);
+        } else if let Some(note) = block.notes.last_mut()
+            && !trimmed.is_empty()
+        {
+            note.push('\n');
+            note.push_str(trimmed);
+        }
+    }
+
+    block
+}
+
+/// Parse a location like `/main.typ:9:28` or `@preview/cetz:0.3.4/src/lib.typ:1:0`.
+fn parse_location(location: &str) -> (String, Option<Position>) {
+    let mut parts = location.rsplitn(3, ':');
+    let column = parts.next().and_then(|column| column.parse::<usize>().ok());
+    let line = parts.next().and_then(|line| line.parse::<usize>().ok());
+    match (parts.next(), line, column) {
+        (Some(file), Some(line), Some(column)) => (
+            file.to_owned(),
+            // Oicana renders columns starting at 0
+            Some(Position {
+                line,
+                column: column + 1,
+            }),
+        ),
+        _ => (location.to_owned(), None),
+    }
+}
+
+/// Move the end of a span to the last caret of an annotation line below a source line.
+///
+/// Single line spans are underlined with carets. For spans over multiple lines, the
+/// start and end are marked with `╭──^` and `╰──^` in an extra column before the source.
+fn extend_span(span: &mut Span, line: usize, content: &str) {
+    let content: Vec<char> = content.chars().skip(1).collect();
+    let Some(last_caret) = content.iter().rposition(|&char| char == '^') else {
+        return;
+    };
+
+    if content.contains(&'╰') {
+        // Skipping the two characters of the extra column, counting from 1 and pointing
+        // behind the caret cancel out
+        span.end = Position {
+            line,
+            column: last_caret,
+        };
+    } else if !content.contains(&'╭') {
+        let carets = content.iter().filter(|&&char| char == '^').count();
+        span.end = Position {
+            line: span.start.line,
+            column: span.start.column + carets,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{Router, body::to_bytes, http::Request, middleware, routing::post};
+    use oicana_input::{
+        CompilationConfig, CompilationMode, TemplateInputs, input::json::JsonInput,
+    };
+    use serde_json::{Value, json};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::cache::init_template;
+
+    /// An error with two calls in its trace, as rendered by Oicana
+    const TRACED_ERROR: &str = r#"error: dictionary does not contain key "missing" and no default value was specified
+  ┌─ /main.typ:2:20
+  │
+2 │ #let lookup(data) = data.at("missing")
+  │                     ^^^^^^^^^^^^^^^^^^
+
+help: error occurred in this call of function `lookup`
+  ┌─ /main.typ:5:2
+  │
+5 │   lookup(data)
+  │   ^^^^^^^^^^^^
+
+help: error occurred in this call of function `wrapper`
+  ┌─ /main.typ:8:1
+  │
+8 │ #wrapper((present: 1))
+  │  ^^^^^^^^^^^^^^^^^^^^^
+
+"#;
+
+    const WARNING: &str = r#"warning: unknown font family: nosuchfont
+  ┌─ /main.typ:1:16
+  │
+1 │ #set text(font: "NoSuchFont")
+  │                 ^^^^^^^^^^^^
+
+"#;
+
+    /// An error with hints, traced to a call spanning several lines
+    const HINTED_ERROR: &str = r#"error: unknown variable: foo
+  ┌─ /main.typ:2:12
+  │
+2 │   $ #body + foo $
+  │             ^^^
+  │
+  = hint: if you meant to display multiple letters as is, try adding spaces between each letter: `f o o`
+  = hint: or if you meant to display this as text, try placing it in quotes: `"foo"`
+
+help: error occurred in this call of function `show-formula`
+  ┌─ /main.typ:5:1
+  │  
+5 │   #show-formula(
+  │ ╭──^
+6 │ │   [x]
+7 │ │ )
+  │ ╰─^
+
+"#;
+
+    fn span(start: (usize, usize), end: (usize, usize)) -> Value {
+        json!({
+            "start": { "line": start.0, "column": start.1 },
+            "end": { "line": end.0, "column": end.1 },
+        })
+    }
+
+    #[test]
+    fn parses_errors_with_their_trace_and_warnings() {
+        let failure = TemplateCompilationFailure {
+            error: TRACED_ERROR.to_owned(),
+            warnings: Some(WARNING.to_owned()),
+        };
+
+        let diagnostics = serde_json::to_value(parse(&failure)).unwrap();
+
+        assert_eq!(
+            diagnostics,
+            json!([
+                {
+                    "severity": "error",
+                    "message": "dictionary does not contain key \"missing\" and no default value was specified",
+                    "file": "/main.typ",
+                    "span": span((2, 21), (2, 39)),
+                    "hints": [],
+                    "trace": [
+                        {
+                            "message": "error occurred in this call of function `lookup`",
+                            "file": "/main.typ",
+                            "span": span((5, 3), (5, 15)),
+                        },
+                        {
+                            "message": "error occurred in this call of function `wrapper`",
+                            "file": "/main.typ",
+                            "span": span((8, 2), (8, 23)),
+                        },
+                    ],
+                },
+                {
+                    "severity": "warning",
+                    "message": "unknown font family: nosuchfont",
+                    "file": "/main.typ",
+                    "span": span((1, 17), (1, 29)),
+                    "hints": [],
+                    "trace": [],
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn parses_hints_and_spans_over_several_lines() {
+        let diagnostics = serde_json::to_value(parse_rendered(HINTED_ERROR)).unwrap();
+
+        assert_eq!(
+            diagnostics,
+            json!([{
+                "severity": "error",
+                "message": "unknown variable: foo",
+                "file": "/main.typ",
+                "span": span((2, 13), (2, 16)),
+                "hints": [
+                    "if you meant to display multiple letters as is, try adding spaces between each letter: `f o o`",
+                    "or if you meant to display this as text, try placing it in quotes: `\"foo\"`",
+                ],
+                "trace": [{
+                    "message": "error occurred in this call of function `show-formula`",
+                    "file": "/main.typ",
+                    "span": span((5, 2), (7, 2)),
+                }],
+            }])
+        );
+    }
+
+    #[test]
+    fn prefers_plain_text_by_quality_and_order() {
+        assert!(prefers_plain_text(&["text/plain"]));
+        assert!(prefers_plain_text(&["text/plain, application/json"]));
+        assert!(prefers_plain_text(&["application/json;q=0.5, text/plain"]));
+        assert!(prefers_plain_text(&[
+            "application/json;q=0.5",
+            "text/plain"
+        ]));
+        assert!(!prefers_plain_text(&["application/json, text/plain"]));
+        assert!(!prefers_plain_text(&["text/plain;q=0.9, application/json"]));
+        assert!(!prefers_plain_text(&["text/plain;q=0, application/json"]));
+        assert!(!prefers_plain_text(&["text/plain;q=0"]));
+        assert!(!prefers_plain_text(&["*/*"]));
+        assert!(!prefers_plain_text(&[]));
+    }
+
+    /// Compile the table template with inputs that it fails on.
+    fn compilation_failure() -> TemplateCompilationFailure {
+        let bytes = std::fs::read("templates/table-0.1.0.zip").unwrap();
+        let mut template = init_template(bytes).unwrap();
+        let mut inputs = TemplateInputs::new();
+        inputs.with_config(CompilationConfig::new(CompilationMode::Production));
+        inputs.with_input(JsonInput::new("data".to_owned(), "{}".to_owned()));
+
+        match template.compile(inputs) {
+            Ok(_) => panic!("The table template compiled without rows"),
+            Err(failure) => failure,
+        }
+    }
+
+    /// Respond to a request like the server does for a failed compilation.
+    async fn respond(failure: &TemplateCompilationFailure, accept: &str) -> Response {
+        let (error, warnings) = (failure.error.clone(), failure.warnings.clone());
+        let app = Router::new()
+            .route(
+                "/",
+                post(move || async move {
+                    failure_response(
+                        StatusCode::BAD_REQUEST,
+                        "Template 'table' failed to compile with given inputs".to_owned(),
+                        TemplateCompilationFailure { error, warnings },
+                    )
+                }),
+            )
+            .layer(middleware::from_fn(negotiate_diagnostics));
+        let request = Request::post("/")
+            .header(header::ACCEPT, accept)
+            .body(axum::body::Body::empty())
+            .unwrap();
+
+        app.oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn renders_compilation_failures_as_requested() {
+        let failure = compilation_failure();
+
+        let response = respond(&failure, "application/json").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: Value =
+            serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap())
+                .unwrap();
+        let diagnostic = &body["diagnostics"][0];
+        assert_eq!(diagnostic["severity"], "error");
+        assert_eq!(
+            diagnostic["message"],
+            "dictionary does not contain key \"description\""
+        );
+        assert_eq!(diagnostic["file"], "/main.typ");
+
+        let response = respond(&failure, "text/plain, application/json").await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "text/plain; charset=utf-8"
+        );
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(text.starts_with(
+            "Template 'table' failed to compile with given inputs: error: dictionary does not contain key \"description\""
+        ));
+        assert!(text.contains("┌─ /main.typ:"));
+    }
+}
@@ -0,0 +1,294 @@
+use std::io::{Cursor, Write};
+
+use axum::http::{HeaderMap, header};
+use oicana_export::{png::export_merged_png, svg::export_merged_svg};
+use serde::Deserialize;
+use typst::layout::PagedDocument;
+use utoipa::IntoParams;
+use uuid::Uuid;
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+use crate::pages::{PageOutOfRange, PageSelection, parse_pages};
+
+/// Points per inch. A scale of 1 pixel per point equals 72 DPI.
+const POINTS_PER_INCH: f32 = 72.0;
+
+/// Largest accepted scale, equal to 720 DPI.
+const MAX_SCALE: f32 = 10.0;
+
+/// Query parameters of PNG previews
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PngQuery {
+    /// Pixels per point, at most 10. Defaults to 1.
+    #[param(example = 2.0)]
+    scale: Option<f32>,
+    /// Resolution in dots per inch, as an alternative to `scale`. 72 DPI equal a scale of 1.
+    #[param(example = 144.0)]
+    dpi: Option<f32>,
+    /// Pages to render, like `1` or `1,3-5`. Pages are counted from 1, `2-` selects all
+    /// pages from the second. Defaults to all pages.
+    #[param(example = "1")]
+    pages: Option<String>,
+    /// Render all selected pages into one image, or each page into its own image. Separate
+    /// images are returned as a zip archive, or as `multipart/mixed` if the request
+    /// accepts it. Defaults to `true`.
+    #[param(example = false)]
+    merge: Option<bool>,
+}
+
+/// Query parameters of SVG exports
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SvgQuery {
+    /// Pages to render, like `1` or `1,3-5`. Pages are counted from 1, `2-` selects all
+    /// pages from the second. Defaults to all pages.
+    #[param(example = "1")]
+    pages: Option<String>,
+    /// Render all selected pages into one image, or each page into its own image. Separate
+    /// images are returned as a zip archive, or as `multipart/mixed` if the request
+    /// accepts it. Defaults to `true`.
+    #[param(example = false)]
+    merge: Option<bool>,
+}
+
+/// Image format to render pages in
+#[derive(Clone, Copy)]
+pub enum ImageFormat {
+    Png { scale: f32 },
+    Svg,
+}
+
+/// How to render the pages of a document as images
+pub struct ImageOptions {
+    pub format: ImageFormat,
+    pub pages: Option<PageSelection>,
+    pub merge: bool,
+}
+
+/// Rendered images with the headers describing them
+pub struct RenderedImages {
+    pub content_type: String,
+    pub disposition: String,
+    pub body: Vec<u8>,
+}
+
+/// Failure to render the pages of a document
+pub enum RenderError {
+    Pages(PageOutOfRange),
+    Export(String),
+}
+
+impl PngQuery {
+    /// Check the query parameters.
+    pub fn options(self) -> Result<ImageOptions, String> {
+        png_options(self.scale, self.dpi, self.pages, self.merge)
+    }
+}
+
+impl SvgQuery {
+    /// Check the query parameters.
+    pub fn options(self) -> Result<ImageOptions, String> {
+        svg_options(self.pages, self.merge)
+    }
+}
+
+/// Check the parameters of a PNG rendering. The scale can be given directly or as DPI.
+pub fn png_options(
+    scale: Option<f32>,
+    dpi: Option<f32>,
+    pages: Option<String>,
+    merge: Option<bool>,
+) -> Result<ImageOptions, String> {
+    let scale = match (scale, dpi) {
+        (Some(_), Some(_)) => return Err("Set either 'scale' or 'dpi', not both.".to_owned()),
+        (Some(scale), None) => scale,
+        (None, Some(dpi)) => dpi / POINTS_PER_INCH,
+        (None, None) => 1.0,
+    };
+    if !(scale > 0.0 && scale <= MAX_SCALE) {
+        return Err(format!(
+            "The scale must be greater than 0 and at most {MAX_SCALE} ({} DPI).",
+            MAX_SCALE * POINTS_PER_INCH
+        ));
+    }
+
+    Ok(ImageOptions {
+        format: ImageFormat::Png { scale },
+        pages: parse_pages(pages)?,
+        merge: merge.unwrap_or(true),
+    })
+}
+
+/// Check the parameters of an SVG rendering.
+pub fn svg_options(pages: Option<String>, merge: Option<bool>) -> Result<ImageOptions, String> {
+    Ok(ImageOptions {
+        format: ImageFormat::Svg,
+        pages: parse_pages(pages)?,
+        merge: merge.unwrap_or(true),
+    })
+}
+
+impl ImageFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png { .. } => "png",
+            ImageFormat::Svg => "svg",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            ImageFormat::Png { .. } => "image/png",
+            ImageFormat::Svg => "image/svg+xml",
+        }
+    }
+
+    /// Render the given pages of a document into one image, from top to bottom.
+    fn render_merged(self, document: &PagedDocument, indices: &[usize]) -> Result<Vec<u8>, String> {
+        let selected = PagedDocument {
+            pages: indices
+                .iter()
+                .map(|&index| document.pages[index].clone())
+                .collect(),
+            ..PagedDocument::default()
+        };
+
+        match self {
+            ImageFormat::Png { scale } => {
+                export_merged_png(&selected, scale).map_err(|error| error.to_string())
+            }
+            ImageFormat::Svg => Ok(export_merged_svg(&selected)),
+        }
+    }
+
+    /// Render each of the given pages of a document into its own image.
+    fn render_pages(
+        self,
+        document: &PagedDocument,
+        indices: &[usize],
+    ) -> Result<Vec<Vec<u8>>, String> {
+        indices
+            .iter()
+            .map(|&index| {
+                let page = &document.pages[index];
+                match self {
+                    ImageFormat::Png { scale } => typst_render::render(page, scale)
+                        .encode_png()
+                        .map_err(|error| error.to_string()),
+                    ImageFormat::Svg => Ok(typst_svg::svg(page).into_bytes()),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Render the selected pages of a document named `name`.
+///
+/// Merged pages are returned as one image. Separate pages are returned as `multipart/mixed`
+/// if `multipart` is set, or as a zip archive otherwise.
+pub fn render(
+    name: &str,
+    document: &PagedDocument,
+    options: &ImageOptions,
+    multipart: bool,
+) -> Result<RenderedImages, RenderError> {
+    let indices = match &options.pages {
+        Some(pages) => pages
+            .indices(document.pages.len())
+            .map_err(RenderError::Pages)?,
+        None => (0..document.pages.len()).collect(),
+    };
+    let format = options.format;
+
+    if options.merge {
+        return Ok(RenderedImages {
+            content_type: format.content_type().to_owned(),
+            disposition: format!("inline; filename=\"{name}.{}\"", format.extension()),
+            body: format
+                .render_merged(document, &indices)
+                .map_err(RenderError::Export)?,
+        });
+    }
+
+    let pages = format
+        .render_pages(document, &indices)
+        .map_err(RenderError::Export)?;
+    if multipart {
+        let (content_type, body) = multipart_pages(name, format, &indices, pages);
+        Ok(RenderedImages {
+            content_type,
+            disposition: "inline".to_owned(),
+            body,
+        })
+    } else {
+        Ok(RenderedImages {
+            content_type: "application/zip".to_owned(),
+            disposition: format!("attachment; filename=\"{name}-pages.zip\""),
+            body: zip_pages(name, format, &indices, pages).map_err(RenderError::Export)?,
+        })
+    }
+}
+
+/// Whether the `Accept` header of a request lists `multipart/mixed`.
+pub fn accepts_multipart(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|accept| accept.to_str().ok())
+        .flat_map(|accept| accept.split(','))
+        .any(|range| range.split(';').next().unwrap_or_default().trim() == "multipart/mixed")
+}
+
+/// Pack rendered pages into a zip archive with one `{name}-{page}.{extension}` per page.
+fn zip_pages(
+    name: &str,
+    format: ImageFormat,
+    indices: &[usize],
+    pages: Vec<Vec<u8>>,
+) -> Result<Vec<u8>, String> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    // PNGs are compressed already and SVGs are small
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+    for (index, page) in indices.iter().zip(pages) {
+        zip.start_file(
+            format!("{name}-{}.{}", index + 1, format.extension()),
+            options,
+        )
+        .map_err(|error| error.to_string())?;
+        zip.write_all(&page).map_err(|error| error.to_string())?;
+    }
+
+    zip.finish()
+        .map(Cursor::into_inner)
+        .map_err(|error| error.to_string())
+}
+
+/// Pack rendered pages into a `multipart/mixed` body with one part per page.
+///
+/// Returns the content type including the boundary, and the body.
+fn multipart_pages(
+    name: &str,
+    format: ImageFormat,
+    indices: &[usize],
+    pages: Vec<Vec<u8>>,
+) -> (String, Vec<u8>) {
+    let boundary = Uuid::new_v4().simple().to_string();
+    let mut body = Vec::new();
+    for (index, page) in indices.iter().zip(pages) {
+        body.extend_from_slice(
+            format!(
+                "--{boundary}\r\nContent-Type: {}\r\nContent-Disposition: attachment; filename=\"{name}-{}.{}\"\r\n\r\n",
+                format.content_type(),
+                index + 1,
+                format.extension()
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(&page);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+
+    (format!("multipart/mixed; boundary={boundary}"), body)
+}
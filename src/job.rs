@@ -0,0 +1,376 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, Instant},
+};
+
+use axum::{body::Bytes, http::StatusCode};
+use reqwest::Url;
+use serde::Serialize;
+use serde_json::{Value, json};
+use tokio::{sync::Semaphore, time::MissedTickBehavior};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::{callback::Callbacks, config::JobConfig, diagnostics::Diagnostic};
+
+/// Render jobs that run in the background of the requests that submitted them.
+///
+/// At most `workers` jobs run at the same time, further jobs wait in a queue of limited
+/// size. Finished jobs and their results are kept for the retention period and dropped
+/// afterwards, whether or not they are requested. Jobs can request a callback once they
+/// finished.
+pub struct JobStore {
+    workers: Arc<Semaphore>,
+    max_queue: usize,
+    retention: Duration,
+    timeout: Duration,
+    callbacks: Callbacks,
+    jobs: Mutex<HashMap<Uuid, Job>>,
+}
+
+struct Job {
+    template_id: String,
+    version: String,
+    state: State,
+}
+
+enum State {
+    Queued,
+    Running,
+    Succeeded {
+        output: JobOutput,
+        finished: Instant,
+    },
+    Failed {
+        failure: JobFailure,
+        finished: Instant,
+    },
+}
+
+/// Document produced by a successful job
+#[derive(Clone)]
+pub struct JobOutput {
+    pub content_type: String,
+    pub disposition: String,
+    pub body: Bytes,
+    /// Warnings of the compilation
+    pub warnings: Vec<Diagnostic>,
+}
+
+/// Error response of a failed job, as a synchronous request would have received it
+#[derive(Clone)]
+pub struct JobFailure {
+    pub status: StatusCode,
+    pub body: Value,
+}
+
+/// What a job produced so far
+pub enum JobOutcome {
+    Unfinished(JobState),
+    Succeeded(JobOutput),
+    Failed(JobFailure),
+}
+
+/// Too many jobs are queued already.
+#[derive(Debug)]
+pub struct QueueFull;
+
+/// State of a job
+#[derive(ToSchema, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    /// Waiting for a free worker
+    Queued,
+    Running,
+    /// The result can be downloaded
+    Succeeded,
+    /// The error is part of the status
+    Failed,
+}
+
+/// Status of a render job
+#[derive(ToSchema, Serialize)]
+pub struct JobStatus {
+    id: Uuid,
+    #[schema(example = "invoice")]
+    template_id: String,
+    #[schema(example = "0.1.0")]
+    version: String,
+    state: JobState,
+    /// Link to the rendered document of a succeeded job
+    #[schema(example = "/jobs/3fa85f64-5717-4562-b3fc-2c963f66afa6/result")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<String>,
+    /// Warnings of a succeeded job
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warnings: Option<Vec<Diagnostic>>,
+    /// HTTP status that a synchronous request would have failed with
+    #[schema(example = 400)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status_code: Option<u16>,
+    /// Error response of a failed job, the same as for a synchronous request. Compilation
+    /// failures include their diagnostics.
+    #[schema(value_type = Option<Object>)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<Value>,
+}
+
+impl JobStore {
+    /// Create the store and start sweeping expired jobs regularly, so their results do not
+    /// stay in memory until the next request for a job.
+    pub fn new(config: &JobConfig) -> Arc<Self> {
+        let store = Arc::new(JobStore {
+            workers: Arc::new(Semaphore::new(config.workers.max(1))),
+            max_queue: config.max_queue,
+            retention: Duration::from_secs(config.retention),
+            timeout: Duration::from_secs(config.timeout),
+            callbacks: Callbacks::new(&config.callback),
+            jobs: Mutex::new(HashMap::new()),
+        });
+        tokio::spawn(sweep_expired(Arc::downgrade(&store)));
+
+        store
+    }
+
+    /// Check a callback URL requested for a job.
+    pub async fn check_callback(&self, url: &str) -> Result<Url, String> {
+        self.callbacks.check_url(url).await
+    }
+
+    /// How long finished jobs are kept.
+    pub fn retention(&self) -> Duration {
+        self.retention
+    }
+
+    /// Queue a job that renders the given template version and start it once a worker is
+    /// free.
+    ///
+    /// `render` is only polled while the job holds a worker. Jobs that take longer than the
+    /// configured time limit fail, see [`crate::timeout::limit_duration`] for what happens to
+    /// their compilation. The status of the finished job is posted to `callback`, if given.
+    pub fn submit<F>(
+        self: &Arc<Self>,
+        template_id: String,
+        version: String,
+        callback: Option<Url>,
+        render: F,
+    ) -> Result<JobStatus, QueueFull>
+    where
+        F: Future<Output = Result<JobOutput, JobFailure>> + Send + 'static,
+    {
+        let id = Uuid::new_v4();
+        let status = {
+            let mut jobs = self.lock();
+            self.remove_expired(&mut jobs);
+            let queued = jobs
+                .values()
+                .filter(|job| matches!(job.state, State::Queued))
+                .count();
+            if queued >= self.max_queue {
+                return Err(QueueFull);
+            }
+            let job = Job {
+                template_id,
+                version,
+                state: State::Queued,
+            };
+            let status = job.status(id);
+            jobs.insert(id, job);
+            status
+        };
+        info!(%id, template_id = %status.template_id, "Queued job {id}");
+
+        let store = self.clone();
+        tokio::spawn(async move {
+            let Ok(worker) = store.workers.clone().acquire_owned().await else {
+                return;
+            };
+            store.set_state(id, State::Running);
+
+            let limit = store.timeout;
+            let outcome = match tokio::time::timeout(limit, render).await {
+                Ok(outcome) => outcome,
+                Err(_) => Err(JobFailure {
+                    status: StatusCode::REQUEST_TIMEOUT,
+                    body: json!({
+                        "message": format!(
+                            "Job {id} was cancelled because it took longer than {} s. A compilation that already started still finishes, but is not exported.",
+                            limit.as_secs()
+                        )
+                    }),
+                }),
+            };
+            let finished = Instant::now();
+            match outcome {
+                Ok(output) => {
+                    info!(%id, "Job {id} succeeded");
+                    store.set_state(id, State::Succeeded { output, finished });
+                }
+                Err(failure) => {
+                    warn!(%id, status = %failure.status, "Job {id} failed");
+                    store.set_state(id, State::Failed { failure, finished });
+                }
+            }
+            drop(worker);
+
+            if let Some(url) = callback
+                && let Some(status) = store.status(id)
+            {
+                store.callbacks.notify(url, status).await;
+            }
+        });
+
+        Ok(status)
+    }
+
+    /// Status of a job, if it is still kept.
+    pub fn status(&self, id: Uuid) -> Option<JobStatus> {
+        let mut jobs = self.lock();
+        self.remove_expired(&mut jobs);
+        jobs.get(&id).map(|job| job.status(id))
+    }
+
+    /// Outcome of a job, if it is still kept.
+    pub fn outcome(&self, id: Uuid) -> Option<JobOutcome> {
+        let mut jobs = self.lock();
+        self.remove_expired(&mut jobs);
+        jobs.get(&id).map(|job| match &job.state {
+            State::Queued => JobOutcome::Unfinished(JobState::Queued),
+            State::Running => JobOutcome::Unfinished(JobState::Running),
+            State::Succeeded { output, .. } => JobOutcome::Succeeded(output.clone()),
+            State::Failed { failure, .. } => JobOutcome::Failed(failure.clone()),
+        })
+    }
+
+    /// Whether the store keeps no jobs.
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    fn set_state(&self, id: Uuid, state: State) {
+        if let Some(job) = self.lock().get_mut(&id) {
+            job.state = state;
+        }
+    }
+
+    fn remove_expired(&self, jobs: &mut HashMap<Uuid, Job>) {
+        jobs.retain(|_, job| match &job.state {
+            State::Queued | State::Running => true,
+            State::Succeeded { finished, .. } | State::Failed { finished, .. } => {
+                finished.elapsed() < self.retention
+            }
+        });
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<Uuid, Job>> {
+        self.jobs
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Bounds of the period between sweeps of expired jobs.
+const MIN_SWEEP_PERIOD: Duration = Duration::from_millis(100);
+const MAX_SWEEP_PERIOD: Duration = Duration::from_secs(60);
+
+/// Remove expired jobs from a store at a tenth of the retention period, until the store is
+/// dropped.
+async fn sweep_expired(store: Weak<JobStore>) {
+    let Some(retention) = store.upgrade().map(|store| store.retention) else {
+        return;
+    };
+    let mut interval =
+        tokio::time::interval((retention / 10).clamp(MIN_SWEEP_PERIOD, MAX_SWEEP_PERIOD));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+        let Some(store) = store.upgrade() else {
+            return;
+        };
+        let mut jobs = store.lock();
+        store.remove_expired(&mut jobs);
+    }
+}
+
+impl Job {
+    fn status(&self, id: Uuid) -> JobStatus {
+        let mut status = JobStatus {
+            id,
+            template_id: self.template_id.clone(),
+            version: self.version.clone(),
+            state: JobState::Queued,
+            result: None,
+            warnings: None,
+            status_code: None,
+            error: None,
+        };
+        match &self.state {
+            State::Queued => {}
+            State::Running => status.state = JobState::Running,
+            State::Succeeded { output, .. } => {
+                status.state = JobState::Succeeded;
+                status.result = Some(format!("/jobs/{id}/result"));
+                status.warnings = Some(output.warnings.clone());
+            }
+            State::Failed { failure, .. } => {
+                status.state = JobState::Failed;
+                status.status_code = Some(failure.status.as_u16());
+                status.error = Some(failure.body.clone());
+            }
+        }
+
+        status
+    }
+}
+
+impl JobStatus {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Make the link to the result absolute, for receivers outside of the service.
+    pub fn linked_from(mut self, base_url: &str) -> Self {
+        self.result = self.result.map(|result| format!("{base_url}{result}"));
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output() -> JobOutput {
+        JobOutput {
+            content_type: "application/pdf".to_owned(),
+            disposition: "attachment; filename=\"table.pdf\"".to_owned(),
+            body: Bytes::from_static(b"%PDF"),
+            warnings: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_expired_jobs_without_requests() {
+        let store = JobStore::new(&JobConfig {
+            retention: 1,
+            ..JobConfig::default()
+        });
+        let status = store
+            .submit("table".to_owned(), "0.1.0".to_owned(), None, async {
+                Ok(output())
+            })
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(matches!(
+            store.lock().get(&status.id()).map(|job| &job.state),
+            Some(State::Succeeded { .. })
+        ));
+
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        assert!(store.lock().is_empty());
+    }
+}
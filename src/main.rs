@@ -1,11 +1,10 @@
 use std::time::Duration;
 
-use axum::http::{Response, StatusCode};
+use axum::{http::Response, middleware};
 use shutdown::shutdown_signal;
 use tower_http::{
     compression::CompressionLayer,
     decompression::RequestDecompressionLayer,
-    timeout::TimeoutLayer,
     trace::{DefaultMakeSpan, TraceLayer},
 };
 use tracing::Span;
@@ -14,12 +13,32 @@ use utoipa::OpenApi;
 use utoipa_axum::router::OpenApiRouter;
 use utoipa_swagger_ui::SwaggerUi;
 
+mod batch;
 mod blob;
+mod cache;
+mod callback;
 mod certificate;
+mod config;
+mod diagnostics;
+mod image;
+mod job;
+mod merge;
+mod openapi;
+mod pages;
+mod pdf;
+mod pool;
+mod render;
+mod schema;
 mod shutdown;
 mod template;
+mod timeout;
+mod warnings;
+mod watcher;
+mod worker;
 
 const TEMPLATE_TAG: &str = "template";
+const COMPILE_TAG: &str = "compile";
+const JOB_TAG: &str = "jobs";
 const CERTIFICATE_TAG: &str = "certificates";
 const BLOB_TAG: &str = "blob";
 
@@ -28,6 +47,8 @@ const BLOB_TAG: &str = "blob";
     external_docs(url = "https://docs.oicana.com", description = "General documentation for Oicana."),
     tags(
         (name = TEMPLATE_TAG, description = "Template API endpoints. Find used templates at https://github.com/oicana/oicana-example-templates."),
+        (name = COMPILE_TAG, description = "Compile a specific template. The request bodies are generated from the input schemas of the templates loaded at startup."),
+        (name = JOB_TAG, description = "Render templates in the background. Poll the status of a job and download its result once it succeeded."),
         (name = CERTIFICATE_TAG, description = "Create certificates"),
         (name = BLOB_TAG, description = "Blob storage endpoints. Upload files (images, documents) to use as template inputs.")
     )
@@ -44,18 +65,49 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    let config = config::Config::load().expect("Failed to load configuration");
+
     let (blob_router, blob_storage) = blob::router();
 
     // For simplicity, this example project will warm-up all templates on startup
-    // all endpoints will expect templates to be in the cache
-    let template_cache = std::sync::Arc::new(template::warmed_up_templates());
+    let template_cache = std::sync::Arc::new(cache::TemplateCache::warmed_up(
+        config.templates_directory.clone(),
+        config.pool,
+    ));
+    let _template_watcher = if config.watch_templates {
+        match watcher::watch_templates(config.templates_directory, template_cache.clone()) {
+            Ok(watcher) => Some(watcher),
+            Err(error) => {
+                tracing::error!("Failed to watch templates, changes require a restart: {error:?}");
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let compile_pool = std::sync::Arc::new(worker::CompilePool::new(config.compile_threads));
+    let warnings = std::sync::Arc::new(warnings::WarningStore::new(config.retained_warnings));
+    let jobs = job::JobStore::new(&config.jobs);
 
     let (router, api) = OpenApiRouter::with_openapi(ApiDoc::openapi())
+        .merge(template::router(
+            blob_storage.clone(),
+            template_cache.clone(),
+            compile_pool.clone(),
+            warnings.clone(),
+            jobs,
+            config.compilation_mode,
+        ))
         .nest(
-            "/templates",
-            template::router(blob_storage.clone(), template_cache.clone()),
+            "/certificates",
+            certificate::router(
+                template_cache.clone(),
+                compile_pool,
+                warnings,
+                config.compilation_mode,
+            ),
         )
-        .nest("/certificates", certificate::router(template_cache))
         .merge(blob_router)
         .layer(
             TraceLayer::new_for_http()
@@ -64,15 +116,18 @@ async fn main() {
                     tracing::info!("Request to took: {:?}", latency);
                 }),
         )
-        .layer(TimeoutLayer::with_status_code(
-            StatusCode::REQUEST_TIMEOUT,
-            Duration::from_secs(1),
+        .layer(middleware::from_fn_with_state(
+            std::sync::Arc::new(config.timeouts),
+            timeout::limit_duration,
         ))
+        .layer(middleware::from_fn(diagnostics::negotiate_diagnostics))
         .layer(RequestDecompressionLayer::new())
         .layer(CompressionLayer::new())
         .split_for_parts();
 
-    let router = router.merge(SwaggerUi::new("/swagger").url("/api/openapi.json", api));
+    let api = openapi::with_template_operations(api, &template_cache).await;
+    let router =
+        router.merge(SwaggerUi::new("/swagger").external_url_unchecked("/api/openapi.json", api));
 
     let app = router.into_make_service();
     let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
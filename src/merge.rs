@@ -0,0 +1,176 @@
+use std::num::NonZeroUsize;
+
+use typst::{
+    foundations::{NativeElement, Smart},
+    introspection::{Introspector, IntrospectorBuilder, Location},
+    layout::{Frame, FrameItem, PagedDocument, Point, Position},
+    model::{Destination, HeadingElem},
+    pdf::AttachElem,
+    text::TextElem,
+    utils::hash128,
+};
+
+/// A compiled document that becomes part of a merged document
+pub struct DocumentPart {
+    /// Title of the bookmark pointing to the first page of the part
+    pub title: String,
+    pub document: PagedDocument,
+}
+
+/// Concatenate the pages of several documents into one.
+///
+/// Elements of the parts may share locations, especially if they are compiled from the same
+/// template, so the merged document does not know them. It only knows one level one heading
+/// per part, which becomes the bookmark of the part, and the files attached by the parts.
+/// Links to locations in a part are replaced by links to their positions.
+///
+/// The metadata of the merged document is taken from the first part.
+pub fn merge_documents(parts: Vec<DocumentPart>) -> PagedDocument {
+    let mut pages = Vec::new();
+    let mut elements = Vec::new();
+    let mut info = None;
+
+    for (index, DocumentPart { title, document }) in parts.into_iter().enumerate() {
+        let offset = pages.len();
+        let start = Position {
+            page: NonZeroUsize::MIN.saturating_add(offset),
+            point: Point::zero(),
+        };
+
+        let mut bookmark = HeadingElem::new(TextElem::packed(title))
+            .with_level(Smart::Custom(NonZeroUsize::MIN))
+            .with_bookmarked(Smart::Custom(true))
+            .pack();
+        bookmark.set_location(Location::new(hash128(&("bookmark", index))));
+        elements.push((bookmark, start));
+
+        let attachments = document.introspector.query(&AttachElem::ELEM.select());
+        for (number, attachment) in attachments.into_iter().enumerate() {
+            let mut attachment = attachment.clone();
+            attachment.set_location(Location::new(hash128(&("attachment", index, number))));
+            elements.push((attachment, start));
+        }
+
+        for page in &document.pages {
+            let mut page = page.clone();
+            page.frame = relink(&page.frame, &document.introspector, offset);
+            pages.push(page);
+        }
+        info.get_or_insert(document.info);
+    }
+
+    let mut introspector = IntrospectorBuilder::new();
+    introspector.pages = pages.len();
+    introspector.page_numberings = pages.iter().map(|page| page.numbering.clone()).collect();
+    introspector.page_supplements = pages.iter().map(|page| page.supplement.clone()).collect();
+
+    PagedDocument {
+        pages,
+        info: info.unwrap_or_default(),
+        introspector: introspector.finalize(elements),
+    }
+}
+
+/// Copy a frame of a part, pointing its links to locations at their positions in the merged
+/// document. Tags are dropped, because their elements are not known to the merged document.
+fn relink(frame: &Frame, introspector: &Introspector, offset: usize) -> Frame {
+    let mut relinked = frame.clone();
+    relinked.clear();
+
+    for (point, item) in frame.items() {
+        let item = match item {
+            FrameItem::Group(group) => {
+                let mut group = group.clone();
+                group.frame = relink(&group.frame, introspector, offset);
+                FrameItem::Group(group)
+            }
+            FrameItem::Link(Destination::Location(location), size) => {
+                let mut position = introspector.position(*location);
+                position.page = position.page.saturating_add(offset);
+                FrameItem::Link(Destination::Position(position), *size)
+            }
+            FrameItem::Tag(_) => continue,
+            item => item.clone(),
+        };
+        relinked.push(*point, item);
+    }
+
+    relinked
+}
+
+#[cfg(test)]
+mod tests {
+    use oicana_input::{CompilationConfig, CompilationMode, TemplateInputs};
+    use typst::foundations::StyleChain;
+
+    use super::*;
+    use crate::cache::init_template;
+
+    /// Compile a template with its development inputs.
+    fn compile(file: &str) -> PagedDocument {
+        let bytes = std::fs::read(format!("templates/{file}")).unwrap();
+        let mut template = init_template(bytes).unwrap();
+        let mut inputs = TemplateInputs::new();
+        inputs.with_config(CompilationConfig::new(CompilationMode::Development));
+
+        template.compile(inputs).unwrap().document
+    }
+
+    #[test]
+    fn concatenates_the_pages_with_one_bookmark_per_part() {
+        let invoice = compile("invoice-0.1.0.zip");
+        let table = compile("table-0.1.0.zip");
+        assert_eq!((invoice.pages.len(), table.pages.len()), (3, 1));
+        let expected_pages: Vec<u128> = invoice
+            .pages
+            .iter()
+            .map(|page| hash128(&relink(&page.frame, &invoice.introspector, 0)))
+            .chain(
+                table
+                    .pages
+                    .iter()
+                    .map(|page| hash128(&relink(&page.frame, &table.introspector, 3))),
+            )
+            .collect();
+
+        let merged = merge_documents(vec![
+            DocumentPart {
+                title: "Invoice".to_owned(),
+                document: invoice,
+            },
+            DocumentPart {
+                title: "Table".to_owned(),
+                document: table,
+            },
+        ]);
+
+        let pages: Vec<u128> = merged
+            .pages
+            .iter()
+            .map(|page| hash128(&page.frame))
+            .collect();
+        assert_eq!(pages, expected_pages);
+
+        let bookmarks: Vec<_> = merged
+            .introspector
+            .query(&HeadingElem::ELEM.select())
+            .into_iter()
+            .map(|heading| {
+                let location = heading.location().unwrap();
+                let heading = heading.to_packed::<HeadingElem>().unwrap();
+                (
+                    heading.body.plain_text().to_string(),
+                    heading.level.get(StyleChain::default()),
+                    merged.introspector.page(location).get(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            bookmarks,
+            [
+                ("Invoice".to_owned(), Smart::Custom(NonZeroUsize::MIN), 1),
+                ("Table".to_owned(), Smart::Custom(NonZeroUsize::MIN), 4),
+            ]
+        );
+    }
+}
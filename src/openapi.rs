@@ -0,0 +1,465 @@
+use std::{collections::HashSet, sync::Arc};
+
+use oicana_input::input_definition::InputDefinition;
+use oicana_template::PdfStandard;
+use serde_json::{Value, json};
+use tracing::{info, warn};
+use utoipa::openapi::OpenApi;
+
+use crate::{
+    cache::{TemplateCache, TemplateKey},
+    pdf::{requires_tags, standard_name},
+    pool::TemplatePool,
+    schema::read_file,
+};
+
+/// Path of the generic compile operation that typed operations are based on.
+const COMPILE_PATH: &str = "/templates/{template_id}/compile";
+
+/// Add a typed compile operation for the latest version of every loaded template.
+///
+/// The generic compile operation accepts any JSON for every input. The generated operations
+/// describe the inputs of each template with the schemas from its manifest. They reflect
+/// the templates that are ready at startup.
+pub async fn with_template_operations(api: OpenApi, template_cache: &TemplateCache) -> Value {
+    let mut api = serde_json::to_value(api).expect("The OpenAPI document is valid JSON");
+    let Some(generic) = api
+        .pointer(&format!("/paths/{}/post", escape(COMPILE_PATH)))
+        .cloned()
+    else {
+        warn!("No generic compile operation found, skipping typed template operations");
+        return api;
+    };
+
+    let mut operation_ids = operation_ids(&api);
+    for (id, _) in template_cache.list() {
+        let Some(key) = template_cache.resolve(&id, None) else {
+            continue;
+        };
+        let Some(pool) = template_cache.get(&key).await else {
+            continue;
+        };
+
+        let (payload, schemas) = compilation_payload(&key, &pool);
+        let example = payload_example(&key, &pool).await;
+        let payload_name = component_name(&[&id, "CompilationPayload"]);
+
+        let mut operation = generic.clone();
+        operation["tags"] = json!([crate::COMPILE_TAG]);
+        operation["operationId"] = json!(operation_id(&id, &mut operation_ids));
+        operation["summary"] = json!(format!("Compile '{id}'"));
+        operation["description"] = json!(format!(
+            "Compile the latest version of template '{id}' with given inputs. The inputs are documented as declared by {key}, the latest version when the service started."
+        ));
+        // The template is part of the path
+        if let Some(Value::Array(parameters)) = operation.get_mut("parameters") {
+            parameters.retain(|parameter| parameter["in"] != "path");
+        }
+        operation["requestBody"]["content"]["application/json"] = json!({
+            "schema": { "$ref": format!("#/components/schemas/{payload_name}") },
+            "example": example,
+        });
+
+        let components = &mut api["components"]["schemas"];
+        components[payload_name] = payload;
+        for (name, schema) in schemas {
+            components[name] = schema;
+        }
+        api["paths"][COMPILE_PATH.replace("{template_id}", &id)] = json!({ "post": operation });
+        info!("Documented typed compile operation for {key}.");
+    }
+
+    api
+}
+
+/// IDs of all operations in an OpenAPI document.
+fn operation_ids(api: &Value) -> HashSet<String> {
+    let Some(Value::Object(paths)) = api.get("paths") else {
+        return HashSet::new();
+    };
+
+    paths
+        .values()
+        .filter_map(Value::as_object)
+        .flat_map(|path| path.values())
+        .filter_map(|operation| operation.get("operationId")?.as_str())
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+/// Operation ID of the typed compile operation of a template, like `compile_invoice`.
+///
+/// Client generators need unique operation IDs. If the ID is taken, for example by the
+/// generic `compile_template` for a template named `template`, a number is added.
+fn operation_id(id: &str, used: &mut HashSet<String>) -> String {
+    let base = format!("compile_{}", id.replace('-', "_"));
+    let operation_id = (1..)
+        .map(|number| match number {
+            1 => base.clone(),
+            number => format!("{base}_{number}"),
+        })
+        .find(|operation_id| !used.contains(operation_id))
+        .expect("Some number is not used yet");
+    used.insert(operation_id.clone());
+
+    operation_id
+}
+
+/// Request body schema for compiling the given template, and the input schemas it refers to.
+fn compilation_payload(key: &TemplateKey, pool: &TemplatePool) -> (Value, Vec<(String, Value)>) {
+    let mut schemas = Vec::new();
+    let mut json_inputs = Vec::new();
+    let mut blob_inputs = Vec::new();
+
+    for definition in &pool.manifest().tool.oicana.inputs {
+        match definition {
+            InputDefinition::Json(input) => {
+                let value = match pool.schemas().get(&input.key) {
+                    Some(schema) => {
+                        let name = component_name(&[&key.id, &input.key]);
+                        schemas.extend(hoist_schema(&name, schema.clone()));
+                        json!({ "$ref": format!("#/components/schemas/{name}") })
+                    }
+                    None => {
+                        json!({ "description": "The input declares no schema and accepts any JSON value." })
+                    }
+                };
+                json_inputs.push(json!({
+                    "type": "object",
+                    "title": input.key,
+                    "description": fallback_description(
+                        input.default.as_deref(),
+                        input.development.as_deref(),
+                    ),
+                    "required": ["key", "value"],
+                    "properties": {
+                        "key": { "type": "string", "const": input.key },
+                        "value": value,
+                    },
+                }));
+            }
+            InputDefinition::Blob(input) => blob_inputs.push(json!({
+                "type": "object",
+                "title": input.key,
+                "description": fallback_description(
+                    input.default.as_ref().map(|fallback| fallback.file.as_str()),
+                    input.development.as_ref().map(|fallback| fallback.file.as_str()),
+                ),
+                "required": ["key", "blobId"],
+                "properties": {
+                    "key": { "type": "string", "const": input.key },
+                    "blobId": {
+                        "type": "string",
+                        "format": "uuid",
+                        "description": "UUID of the blob from the blob storage",
+                    },
+                },
+            })),
+        }
+    }
+
+    let payload = json!({
+        "type": "object",
+        "description": format!("Inputs and config to compile {key}"),
+        "required": ["jsonInputs"],
+        "properties": {
+            "mode": { "$ref": "#/components/schemas/Mode" },
+            "jsonInputs": { "type": "array", "items": one_of(json_inputs) },
+            "blobInputs": { "type": "array", "items": one_of(blob_inputs) },
+            "pdfStandards": {
+                "type": "array",
+                "items": { "type": "string" },
+                "description": pdf_standards_description(&pool.manifest().tool.oicana.export.pdf.standards),
+            },
+        },
+    });
+
+    (payload, schemas)
+}
+
+/// Example request body with the default or development values of the JSON inputs.
+async fn payload_example(key: &TemplateKey, pool: &Arc<TemplatePool>) -> Value {
+    let mut json_inputs = Vec::new();
+    let Ok(template) = pool.checkout().await else {
+        return json!({ "jsonInputs": json_inputs });
+    };
+
+    for definition in &pool.manifest().tool.oicana.inputs {
+        let InputDefinition::Json(input) = definition else {
+            continue;
+        };
+        let Some(file) = input.default.as_ref().or(input.development.as_ref()) else {
+            continue;
+        };
+        match read_file(&template, file).and_then(|bytes| {
+            serde_json::from_slice::<Value>(&bytes).map_err(|error| error.to_string())
+        }) {
+            Ok(value) => json_inputs.push(json!({ "key": input.key, "value": value })),
+            Err(error) => {
+                warn!("Failed to read '{file}' of {key} for the API documentation: {error}")
+            }
+        }
+    }
+
+    json!({ "jsonInputs": json_inputs })
+}
+
+/// Describe which value an input falls back to if a request does not contain it.
+fn fallback_description(default: Option<&str>, development: Option<&str>) -> String {
+    match (default, development) {
+        (Some(default), _) => format!("Optional, defaults to `{default}`."),
+        (None, Some(development)) => {
+            format!("Required in production mode. Development mode falls back to `{development}`.")
+        }
+        (None, None) => "Required.".to_owned(),
+    }
+}
+
+/// Describe the PDF standards a request can export the template with.
+fn pdf_standards_description(declared: &[PdfStandard]) -> String {
+    let mut description = format!(
+        "PDF standards to conform to, like `a-2b` or `ua-1`. Only used for PDF output. Defaults to the standards of the template: {}.",
+        code_names(declared.iter())
+    );
+    if requires_tags(declared) {
+        let accessible = declared
+            .iter()
+            .filter(|standard| requires_tags(&[**standard]));
+        description.push_str(&format!(
+            " Requests have to keep {}, because the template is written to be accessible.",
+            code_names(accessible)
+        ));
+    }
+
+    description
+}
+
+fn code_names<'a>(standards: impl Iterator<Item = &'a PdfStandard>) -> String {
+    standards
+        .map(|standard| format!("`{}`", standard_name(*standard)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Schema matching exactly one of the given schemas, or nothing if there are none.
+fn one_of(schemas: Vec<Value>) -> Value {
+    if schemas.is_empty() {
+        json!({ "not": {} })
+    } else {
+        json!({ "oneOf": schemas })
+    }
+}
+
+/// Turn an input schema into components of the OpenAPI document.
+///
+/// References inside the schema point to its own root. They are rewritten to point into
+/// the components, and the definitions of the schema become components of their own.
+fn hoist_schema(name: &str, mut schema: Value) -> Vec<(String, Value)> {
+    let mut components = Vec::new();
+    if let Some(root) = schema.as_object_mut() {
+        root.remove("$schema");
+        root.remove("$id");
+        for keyword in ["$defs", "definitions"] {
+            let Some(Value::Object(definitions)) = root.remove(keyword) else {
+                continue;
+            };
+            for (definition, mut schema) in definitions {
+                rewrite_references(name, &mut schema);
+                components.push((component_name(&[name, &definition]), schema));
+            }
+        }
+    }
+    rewrite_references(name, &mut schema);
+    components.push((name.to_owned(), schema));
+
+    components
+}
+
+/// Rewrite references relative to the schema root to point to the schema's components.
+fn rewrite_references(name: &str, value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            if let Some(Value::String(reference)) = object.get_mut("$ref")
+                && let Some(pointer) = reference.strip_prefix('#')
+            {
+                *reference = component_reference(name, pointer);
+            }
+            object
+                .values_mut()
+                .for_each(|value| rewrite_references(name, value));
+        }
+        Value::Array(values) => values
+            .iter_mut()
+            .for_each(|value| rewrite_references(name, value)),
+        _ => {}
+    }
+}
+
+/// Map a JSON pointer into an input schema to a reference into the components.
+fn component_reference(name: &str, pointer: &str) -> String {
+    let mut segments = pointer.splitn(4, '/').skip(1);
+    if let (Some("$defs" | "definitions"), Some(definition)) = (segments.next(), segments.next()) {
+        let definition = definition.replace("~1", "/").replace("~0", "~");
+        let rest = segments
+            .next()
+            .map(|rest| format!("/{rest}"))
+            .unwrap_or_default();
+        return format!(
+            "#/components/schemas/{}{rest}",
+            component_name(&[name, &definition])
+        );
+    }
+
+    format!("#/components/schemas/{name}{pointer}")
+}
+
+/// Join parts to a component name, replacing characters OpenAPI does not allow.
+fn component_name(parts: &[&str]) -> String {
+    parts
+        .join(".")
+        .chars()
+        .map(|char| {
+            if char.is_ascii_alphanumeric() || matches!(char, '.' | '-' | '_') {
+                char
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Escape a path for use in a JSON pointer.
+fn escape(path: &str) -> String {
+    path.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hoists_definitions_into_components() {
+        let schema = json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "$id": "data.schema.json",
+            "type": "object",
+            "properties": {
+                "rows": { "type": "array", "items": { "$ref": "#/$defs/row" } },
+                "total": { "$ref": "#/definitions/money" },
+                "copy": { "$ref": "#" },
+            },
+            "$defs": {
+                "row": {
+                    "type": "object",
+                    "properties": {
+                        "price": { "$ref": "#/definitions/money" },
+                        "children": { "type": "array", "items": { "$ref": "#/$defs/row" } },
+                    },
+                },
+            },
+            "definitions": {
+                "money": { "type": "number" },
+            },
+        });
+
+        let components = hoist_schema("table.data", schema);
+
+        assert_eq!(
+            components,
+            [
+                (
+                    "table.data.row".to_owned(),
+                    json!({
+                        "type": "object",
+                        "properties": {
+                            "price": { "$ref": "#/components/schemas/table.data.money" },
+                            "children": {
+                                "type": "array",
+                                "items": { "$ref": "#/components/schemas/table.data.row" },
+                            },
+                        },
+                    })
+                ),
+                ("table.data.money".to_owned(), json!({ "type": "number" })),
+                (
+                    "table.data".to_owned(),
+                    json!({
+                        "type": "object",
+                        "properties": {
+                            "rows": {
+                                "type": "array",
+                                "items": { "$ref": "#/components/schemas/table.data.row" },
+                            },
+                            "total": { "$ref": "#/components/schemas/table.data.money" },
+                            "copy": { "$ref": "#/components/schemas/table.data" },
+                        },
+                    })
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn rewrites_local_references_only() {
+        let mut schema = json!({
+            "allOf": [
+                { "$ref": "#/properties/name" },
+                { "$ref": "https://example.com/schema.json" },
+            ],
+            "properties": { "name": { "type": "string" } },
+        });
+
+        rewrite_references("invoice.buyer", &mut schema);
+
+        assert_eq!(
+            schema["allOf"],
+            json!([
+                { "$ref": "#/components/schemas/invoice.buyer/properties/name" },
+                { "$ref": "https://example.com/schema.json" },
+            ])
+        );
+    }
+
+    #[test]
+    fn references_definitions_by_their_component() {
+        assert_eq!(
+            component_reference("table.data", ""),
+            "#/components/schemas/table.data"
+        );
+        assert_eq!(
+            component_reference("table.data", "/$defs/row"),
+            "#/components/schemas/table.data.row"
+        );
+        assert_eq!(
+            component_reference("table.data", "/definitions/a~1b~0c/properties/name"),
+            "#/components/schemas/table.data.a_b_c/properties/name"
+        );
+        assert_eq!(
+            component_reference("table.data", "/properties/rows/items"),
+            "#/components/schemas/table.data/properties/rows/items"
+        );
+    }
+
+    #[test]
+    fn numbers_colliding_operation_ids() {
+        let api = json!({
+            "paths": {
+                "/templates/{template_id}/compile": {
+                    "post": { "operationId": "compile_template" },
+                },
+                "/templates/{template_id}/versions/{version}/compile": {
+                    "post": { "operationId": "compile_template_version" },
+                },
+            },
+        });
+        let mut used = operation_ids(&api);
+
+        assert_eq!(operation_id("invoice", &mut used), "compile_invoice");
+        assert_eq!(operation_id("template", &mut used), "compile_template_2");
+        assert_eq!(operation_id("my-invoice", &mut used), "compile_my_invoice");
+        assert_eq!(
+            operation_id("my_invoice", &mut used),
+            "compile_my_invoice_2"
+        );
+    }
+}
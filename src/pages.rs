@@ -0,0 +1,206 @@
+use std::{fmt, str::FromStr};
+
+/// Pages of a document selected by a request, like `1`, `1,3-5` or `2-`.
+///
+/// Pages are counted from 1. Ranges include their end, open ranges go to the last page.
+/// Every page can only be selected once.
+pub struct PageSelection(Vec<PageRange>);
+
+struct PageRange {
+    start: usize,
+    end: Option<usize>,
+}
+
+/// A selected page that the document does not have.
+#[derive(Debug)]
+pub struct PageOutOfRange {
+    pub page: usize,
+    pub count: usize,
+}
+
+impl PageSelection {
+    /// Indices of the selected pages in a document with `count` pages, in the order of the
+    /// selection.
+    pub fn indices(&self, count: usize) -> Result<Vec<usize>, PageOutOfRange> {
+        let mut indices = Vec::new();
+        for range in &self.0 {
+            let end = range.end.unwrap_or(count.max(range.start));
+            if end > count {
+                return Err(PageOutOfRange { page: end, count });
+            }
+            indices.extend(range.start - 1..end);
+        }
+
+        Ok(indices)
+    }
+}
+
+/// Parse an optional page selection from a request.
+pub fn parse_pages(pages: Option<String>) -> Result<Option<PageSelection>, String> {
+    pages
+        .map(|pages| {
+            pages
+                .parse::<PageSelection>()
+                .map_err(|error| format!("Invalid page selection '{pages}': {error}."))
+        })
+        .transpose()
+}
+
+impl FromStr for PageSelection {
+    type Err = String;
+
+    fn from_str(selection: &str) -> Result<Self, Self::Err> {
+        let ranges = selection
+            .split(',')
+            .map(|range| {
+                let range = range.trim();
+                let (start, end) = match range.split_once('-') {
+                    Some((start, "")) => (page_number(start)?, None),
+                    Some((start, end)) => (page_number(start)?, Some(page_number(end)?)),
+                    None => (page_number(range)?, Some(page_number(range)?)),
+                };
+                if end.is_some_and(|end| end < start) {
+                    return Err(format!("the range '{range}' ends before it starts"));
+                }
+
+                Ok(PageRange { start, end })
+            })
+            .collect::<Result<Vec<PageRange>, String>>()?;
+
+        for (index, range) in ranges.iter().enumerate() {
+            if let Some(page) = ranges[..index]
+                .iter()
+                .find_map(|earlier| range.overlap(earlier))
+            {
+                return Err(format!("page {page} is selected more than once"));
+            }
+        }
+
+        Ok(PageSelection(ranges))
+    }
+}
+
+impl PageRange {
+    /// The first page that both ranges select, if any. Open ranges select all pages from
+    /// their start, whatever the length of the document.
+    fn overlap(&self, other: &PageRange) -> Option<usize> {
+        let start = self.start.max(other.start);
+        let end = match (self.end, other.end) {
+            (Some(end), Some(other_end)) => end.min(other_end),
+            (Some(end), None) | (None, Some(end)) => end,
+            (None, None) => start,
+        };
+
+        (start <= end).then_some(start)
+    }
+}
+
+impl fmt::Display for PageOutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.count {
+            1 => write!(
+                f,
+                "page {} does not exist, the document has 1 page",
+                self.page
+            ),
+            count => write!(
+                f,
+                "page {} does not exist, the document has {count} pages",
+                self.page
+            ),
+        }
+    }
+}
+
+fn page_number(page: &str) -> Result<usize, String> {
+    match page.trim().parse::<usize>() {
+        Ok(0) => Err("pages are counted from 1".to_owned()),
+        Ok(page) => Ok(page),
+        Err(_) => Err(format!("'{}' is not a page number", page.trim())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn indices(selection: &str, count: usize) -> Result<Vec<usize>, String> {
+        let selection: PageSelection = selection.parse()?;
+        selection.indices(count).map_err(|error| error.to_string())
+    }
+
+    #[test]
+    fn selects_pages_and_ranges_in_order() {
+        assert_eq!(indices("1-3,5", 5), Ok(vec![0, 1, 2, 4]));
+        assert_eq!(indices("5, 1-2", 5), Ok(vec![4, 0, 1]));
+        assert_eq!(indices("2", 2), Ok(vec![1]));
+    }
+
+    #[test]
+    fn open_ranges_go_to_the_last_page() {
+        assert_eq!(indices("2-", 4), Ok(vec![1, 2, 3]));
+        assert_eq!(indices("4-", 4), Ok(vec![3]));
+    }
+
+    #[test]
+    fn rejects_invalid_pages() {
+        assert_eq!(indices("0", 3), Err("pages are counted from 1".to_owned()));
+        assert_eq!(
+            indices("3-1", 3),
+            Err("the range '3-1' ends before it starts".to_owned())
+        );
+        assert_eq!(
+            indices("1,,3", 3),
+            Err("'' is not a page number".to_owned())
+        );
+        assert_eq!(indices("", 3), Err("'' is not a page number".to_owned()));
+        assert_eq!(
+            indices("a-2", 3),
+            Err("'a' is not a page number".to_owned())
+        );
+        assert_eq!(
+            indices("99999999999999999999999", 3),
+            Err("'99999999999999999999999' is not a page number".to_owned())
+        );
+    }
+
+    #[test]
+    fn rejects_pages_selected_more_than_once() {
+        assert_eq!(
+            indices("1,1-2", 3),
+            Err("page 1 is selected more than once".to_owned())
+        );
+        assert_eq!(
+            indices("2-3,3", 3),
+            Err("page 3 is selected more than once".to_owned())
+        );
+        assert_eq!(
+            indices("2-,5", 6),
+            Err("page 5 is selected more than once".to_owned())
+        );
+        assert_eq!(
+            indices("3-,1-", 6),
+            Err("page 3 is selected more than once".to_owned())
+        );
+        assert_eq!(indices("3-,1-2", 4), Ok(vec![2, 3, 0, 1]));
+    }
+
+    #[test]
+    fn rejects_pages_beyond_the_document() {
+        assert_eq!(
+            indices("2-5", 3),
+            Err("page 5 does not exist, the document has 3 pages".to_owned())
+        );
+        assert_eq!(
+            indices("2-", 1),
+            Err("page 2 does not exist, the document has 1 page".to_owned())
+        );
+        assert_eq!(
+            indices(&usize::MAX.to_string(), 1),
+            Err(format!(
+                "page {} does not exist, the document has 1 page",
+                usize::MAX
+            ))
+        );
+    }
+}
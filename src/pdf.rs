@@ -0,0 +1,441 @@
+use std::num::NonZeroUsize;
+
+use oicana_template::PdfStandard;
+use oicana_world::diagnostics::TemplateDiagnostics;
+use serde::Deserialize;
+use serde_json::Value;
+use typst::{
+    ecow::EcoString,
+    foundations::Smart,
+    layout::{PageRanges, PagedDocument},
+};
+use typst_pdf::{PdfOptions, PdfStandards};
+use utoipa::IntoParams;
+
+/// Query parameters of PDF exports
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PdfQuery {
+    /// Pages to export, like `1` or `1-3,5`. Pages are counted from 1, `2-` selects all
+    /// pages from the second. Pages keep their order in the document and can only be selected
+    /// once. Defaults to all pages.
+    #[param(example = "1-3,5")]
+    pub pages: Option<String>,
+}
+
+/// Export a document as PDF.
+///
+/// Whole documents are exported by `export_merged_pdf` of Oicana. It cannot restrict the
+/// export to some pages, so selections of `pages` are exported here with the same options,
+/// except for `page_ranges` and `tagged`: Typst cannot tag a subset of the pages, so only
+/// whole documents are tagged, see [`requires_tags`]. `pages` are indices of the pages to
+/// export.
+pub fn export_pdf<Diagnostics: TemplateDiagnostics>(
+    document: &PagedDocument,
+    diagnostics: &Diagnostics,
+    standards: &[PdfStandard],
+    pages: Option<&[usize]>,
+) -> Result<Vec<u8>, String> {
+    let Some(pages) = pages else {
+        return oicana_export::pdf::export_merged_pdf(document, diagnostics, standards);
+    };
+    let page_ranges = PageRanges::new(
+        pages
+            .iter()
+            .map(|index| {
+                let page = NonZeroUsize::new(index + 1);
+                page..=page
+            })
+            .collect(),
+    );
+
+    let options = PdfOptions {
+        ident: Smart::Auto,
+        timestamp: None,
+        page_ranges: Some(page_ranges),
+        tagged: false,
+        standards: typst_standards(standards)
+            .map_err(|error| format!("Invalid combination of PDF standards: {error}"))?,
+    };
+
+    typst_pdf::pdf(document, &options).map_err(|source_error| {
+        String::from_utf8_lossy(&diagnostics.format_diagnostics(source_error)).into()
+    })
+}
+
+/// Export a document merged from several templates as untagged PDF.
+///
+/// Unlike `export_merged_pdf` of Oicana, which always tags the PDF, merged documents are never
+/// tagged, because they do not know the elements of their parts, see
+/// [`crate::merge::merge_documents`]. Export errors are reported without their source, because
+/// it belongs to one of the merged templates.
+pub fn export_untagged_pdf(
+    document: &PagedDocument,
+    standards: &[PdfStandard],
+) -> Result<Vec<u8>, String> {
+    let options = PdfOptions {
+        ident: Smart::Auto,
+        timestamp: None,
+        page_ranges: None,
+        tagged: false,
+        standards: typst_standards(standards)
+            .map_err(|error| format!("Invalid combination of PDF standards: {error}"))?,
+    };
+
+    typst_pdf::pdf(document, &options).map_err(|errors| {
+        errors
+            .iter()
+            .map(|error| {
+                let mut message = format!("error: {}", error.message);
+                for hint in &error.hints {
+                    message.push_str(&format!("\n  = hint: {hint}"));
+                }
+                message
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    })
+}
+
+/// The strictest of the given standards, for a PDF that has to conform to all of them.
+///
+/// Keeps the lowest PDF version and the PDF/A standard that is at least as strict as all
+/// others: PDF/A-1 is stricter than PDF/A-2, which is stricter than PDF/A-3, and level `a` is
+/// stricter than `u`, which is stricter than `b`.
+///
+/// Fails if no PDF/A standard is the strictest, like for `a-1b` and `a-2u`, or if the
+/// strictest standards cannot be combined.
+pub fn strictest_standards(standards: &[PdfStandard]) -> Result<Vec<PdfStandard>, String> {
+    let mut strictest = Vec::new();
+
+    // Readers of a PDF version also read the versions before it, so the lowest version is the
+    // one that every part can be read in. The standards are ordered by version.
+    let version = SUPPORTED_STANDARDS
+        .into_iter()
+        .filter(|standard| is_version(*standard))
+        .find(|version| standards.contains(version));
+    strictest.extend(version);
+
+    let archives: Vec<PdfStandard> = SUPPORTED_STANDARDS
+        .into_iter()
+        .filter(|standard| archive_part(*standard).is_some() && standards.contains(standard))
+        .collect();
+    if !archives.is_empty() {
+        let archive = archives
+            .iter()
+            .copied()
+            .find(|candidate| {
+                archives
+                    .iter()
+                    .all(|other| at_least_as_strict(*candidate, *other))
+            })
+            .ok_or_else(|| {
+                format!(
+                    "None of the PDF/A standards {} is stricter than the others.",
+                    names(&archives)
+                )
+            })?;
+        strictest.push(archive);
+    }
+
+    if standards.contains(&PdfStandard::Ua_1) {
+        strictest.push(PdfStandard::Ua_1);
+    }
+    typst_standards(&strictest).map_err(|error| {
+        format!(
+            "The strictest PDF standards {} cannot be combined: {error}.",
+            names(&strictest)
+        )
+    })?;
+
+    Ok(strictest)
+}
+
+fn is_version(standard: PdfStandard) -> bool {
+    matches!(
+        standard,
+        PdfStandard::V_1_4
+            | PdfStandard::V_1_5
+            | PdfStandard::V_1_6
+            | PdfStandard::V_1_7
+            | PdfStandard::V_2_0
+    )
+}
+
+/// Part and conformance level of a PDF/A standard, with `b` as 0, `u` as 1 and `a` as 2.
+///
+/// PDF/A-4 has no levels, its variants are only as strict as themselves.
+fn archive_part(standard: PdfStandard) -> Option<(u8, Option<u8>)> {
+    match standard {
+        PdfStandard::A_1b => Some((1, Some(0))),
+        PdfStandard::A_1a => Some((1, Some(2))),
+        PdfStandard::A_2b => Some((2, Some(0))),
+        PdfStandard::A_2u => Some((2, Some(1))),
+        PdfStandard::A_2a => Some((2, Some(2))),
+        PdfStandard::A_3b => Some((3, Some(0))),
+        PdfStandard::A_3u => Some((3, Some(1))),
+        PdfStandard::A_3a => Some((3, Some(2))),
+        PdfStandard::A_4 | PdfStandard::A_4f | PdfStandard::A_4e => Some((4, None)),
+        _ => None,
+    }
+}
+
+fn at_least_as_strict(standard: PdfStandard, other: PdfStandard) -> bool {
+    match (archive_part(standard), archive_part(other)) {
+        _ if standard == other => true,
+        (Some((part, Some(level))), Some((other_part, Some(other_level)))) => {
+            part <= other_part && level >= other_level
+        }
+        _ => false,
+    }
+}
+
+/// Standards of a PDF export.
+///
+/// Requested standards replace the standards declared in the manifest of the template, which
+/// are used if none are requested. Standards that require a tagged PDF are an exception: a
+/// template declaring them is written to be accessible, so requests have to keep them.
+///
+/// Fails if a requested standard is not supported, if the requested standards cannot be
+/// combined, or if they drop an accessibility standard of the template.
+pub fn export_standards(
+    template: &[PdfStandard],
+    requested: &[String],
+) -> Result<Vec<PdfStandard>, String> {
+    if requested.is_empty() {
+        return Ok(template.to_vec());
+    }
+
+    let mut standards = Vec::new();
+    for name in requested {
+        let standard = SUPPORTED_STANDARDS
+            .into_iter()
+            .find(|standard| standard_name(*standard) == *name)
+            .ok_or_else(|| {
+                format!(
+                    "'{name}' is not a supported PDF standard. Supported standards are {}.",
+                    names(&SUPPORTED_STANDARDS)
+                )
+            })?;
+        if !standards.contains(&standard) {
+            standards.push(standard);
+        }
+    }
+    typst_standards(&standards).map_err(|error| {
+        format!(
+            "The requested PDF standards {} cannot be combined: {error}.",
+            names(&standards)
+        )
+    })?;
+
+    let dropped: Vec<PdfStandard> = template
+        .iter()
+        .copied()
+        .filter(|standard| requires_tags(&[*standard]) && !standards.contains(standard))
+        .collect();
+    if !dropped.is_empty() {
+        return Err(format!(
+            "The template is written to conform to {}, but the requested PDF standards {} do not include it.",
+            names(&dropped),
+            names(&standards)
+        ));
+    }
+
+    Ok(standards)
+}
+
+/// Whether one of the standards requires a tagged PDF.
+pub fn requires_tags(standards: &[PdfStandard]) -> bool {
+    standards.iter().any(|standard| {
+        matches!(
+            standard,
+            PdfStandard::A_1a | PdfStandard::A_2a | PdfStandard::A_3a | PdfStandard::Ua_1
+        )
+    })
+}
+
+/// All standards that the PDF export supports
+const SUPPORTED_STANDARDS: [PdfStandard; 17] = [
+    PdfStandard::V_1_4,
+    PdfStandard::V_1_5,
+    PdfStandard::V_1_6,
+    PdfStandard::V_1_7,
+    PdfStandard::V_2_0,
+    PdfStandard::A_1b,
+    PdfStandard::A_1a,
+    PdfStandard::A_2b,
+    PdfStandard::A_2u,
+    PdfStandard::A_2a,
+    PdfStandard::A_3b,
+    PdfStandard::A_3u,
+    PdfStandard::A_3a,
+    PdfStandard::A_4,
+    PdfStandard::A_4f,
+    PdfStandard::A_4e,
+    PdfStandard::Ua_1,
+];
+
+/// Name of a standard as used in manifests and requests, like `a-2b`.
+pub fn standard_name(standard: PdfStandard) -> String {
+    match serde_json::to_value(standard) {
+        Ok(Value::String(name)) => name,
+        _ => format!("{standard:?}"),
+    }
+}
+
+fn names(standards: &[PdfStandard]) -> String {
+    standards
+        .iter()
+        .map(|standard| format!("'{}'", standard_name(*standard)))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn typst_standards(standards: &[PdfStandard]) -> Result<PdfStandards, EcoString> {
+    let standards: Vec<_> = standards
+        .iter()
+        .map(|standard| to_typst_standard(*standard))
+        .collect();
+    PdfStandards::new(&standards)
+}
+
+/// The same mapping as in `oicana_export`, where it is private. Standards are converted here
+/// to validate them before compiling, and for exports that Oicana does not cover.
+fn to_typst_standard(standard: PdfStandard) -> typst_pdf::PdfStandard {
+    match standard {
+        PdfStandard::V_1_4 => typst_pdf::PdfStandard::V_1_4,
+        PdfStandard::V_1_5 => typst_pdf::PdfStandard::V_1_5,
+        PdfStandard::V_1_6 => typst_pdf::PdfStandard::V_1_6,
+        PdfStandard::V_1_7 => typst_pdf::PdfStandard::V_1_7,
+        PdfStandard::V_2_0 => typst_pdf::PdfStandard::V_2_0,
+        PdfStandard::A_1b => typst_pdf::PdfStandard::A_1b,
+        PdfStandard::A_1a => typst_pdf::PdfStandard::A_1a,
+        PdfStandard::A_2b => typst_pdf::PdfStandard::A_2b,
+        PdfStandard::A_2u => typst_pdf::PdfStandard::A_2u,
+        PdfStandard::A_2a => typst_pdf::PdfStandard::A_2a,
+        PdfStandard::A_3b => typst_pdf::PdfStandard::A_3b,
+        PdfStandard::A_3u => typst_pdf::PdfStandard::A_3u,
+        PdfStandard::A_3a => typst_pdf::PdfStandard::A_3a,
+        PdfStandard::A_4 => typst_pdf::PdfStandard::A_4,
+        PdfStandard::A_4f => typst_pdf::PdfStandard::A_4f,
+        PdfStandard::A_4e => typst_pdf::PdfStandard::A_4e,
+        PdfStandard::Ua_1 => typst_pdf::PdfStandard::Ua_1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requested(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn names_match_the_manifest() {
+        assert_eq!(standard_name(PdfStandard::A_2b), "a-2b");
+        assert_eq!(standard_name(PdfStandard::V_1_7), "1.7");
+        assert_eq!(standard_name(PdfStandard::Ua_1), "ua-1");
+        for standard in SUPPORTED_STANDARDS {
+            let name = standard_name(standard);
+            assert_eq!(
+                serde_json::from_value::<PdfStandard>(Value::String(name)).ok(),
+                Some(standard)
+            );
+        }
+    }
+
+    #[test]
+    fn uses_the_template_standards_without_request() {
+        assert_eq!(
+            export_standards(&[PdfStandard::A_3b], &[]),
+            Ok(vec![PdfStandard::A_3b])
+        );
+    }
+
+    #[test]
+    fn requested_standards_replace_those_of_the_template() {
+        assert_eq!(
+            export_standards(&[PdfStandard::A_3b], &requested(&["a-2b", "1.7", "a-2b"])),
+            Ok(vec![PdfStandard::A_2b, PdfStandard::V_1_7])
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_standards() {
+        let error = export_standards(&[PdfStandard::A_3b], &requested(&["a-9"])).unwrap_err();
+        assert!(
+            error.starts_with(
+                "'a-9' is not a supported PDF standard. Supported standards are '1.4', "
+            ),
+            "{error}"
+        );
+        assert!(export_standards(&[], &requested(&["A-2B"])).is_err());
+        assert!(export_standards(&[], &requested(&[""])).is_err());
+    }
+
+    #[test]
+    fn rejects_standards_that_cannot_be_combined() {
+        let error = export_standards(&[], &requested(&["a-2b", "a-3b"])).unwrap_err();
+        assert!(
+            error.starts_with("The requested PDF standards 'a-2b', 'a-3b' cannot be combined: "),
+            "{error}"
+        );
+        assert!(export_standards(&[], &requested(&["1.4", "1.7"])).is_err());
+    }
+
+    #[test]
+    fn keeps_accessibility_standards_of_the_template() {
+        assert_eq!(
+            export_standards(&[PdfStandard::Ua_1], &requested(&["a-2b"])),
+            Err("The template is written to conform to 'ua-1', but the requested PDF standards 'a-2b' do not include it.".to_owned())
+        );
+        assert_eq!(
+            export_standards(&[PdfStandard::Ua_1], &requested(&["ua-1", "1.7"])),
+            Ok(vec![PdfStandard::Ua_1, PdfStandard::V_1_7])
+        );
+    }
+
+    #[test]
+    fn merges_into_the_strictest_archive_standard() {
+        use PdfStandard::*;
+        assert_eq!(strictest_standards(&[A_3b, A_2b, A_3b]), Ok(vec![A_2b]));
+        assert_eq!(strictest_standards(&[A_2b, A_2u]), Ok(vec![A_2u]));
+        assert_eq!(strictest_standards(&[A_3u, A_1a, A_2b]), Ok(vec![A_1a]));
+        assert_eq!(strictest_standards(&[A_4f, A_4f]), Ok(vec![A_4f]));
+        assert_eq!(strictest_standards(&[]), Ok(vec![]));
+    }
+
+    #[test]
+    fn merges_into_the_lowest_pdf_version() {
+        use PdfStandard::*;
+        assert_eq!(strictest_standards(&[V_1_7, V_1_4, V_2_0]), Ok(vec![V_1_4]));
+        assert_eq!(
+            strictest_standards(&[V_1_7, A_3b, A_2b]),
+            Ok(vec![V_1_7, A_2b])
+        );
+    }
+
+    #[test]
+    fn rejects_conflicting_standards_of_parts() {
+        use PdfStandard::*;
+        // Neither is at least as strict as the other
+        assert_eq!(
+            strictest_standards(&[A_1b, A_2u]),
+            Err(
+                "None of the PDF/A standards 'a-1b', 'a-2u' is stricter than the others."
+                    .to_owned()
+            )
+        );
+        assert!(strictest_standards(&[A_2b, A_3u]).is_err());
+        // PDF/A-4 has no levels to compare
+        assert!(strictest_standards(&[A_4, A_2b]).is_err());
+        assert!(strictest_standards(&[A_4, A_4f]).is_err());
+        // PDF/A-1 is based on PDF 1.4
+        assert_eq!(
+            strictest_standards(&[A_1b, V_1_7]),
+            Err("The strictest PDF standards '1.7', 'a-1b' cannot be combined: PDF 1.7 is not compatible with PDF/A-1b.".to_owned())
+        );
+        assert!(strictest_standards(&[V_2_0, A_3b, A_2b]).is_err());
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use oicana::Template;
+use oicana_files::packed::PackedTemplate;
+use oicana_template::manifest::TemplateManifest;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::schema::InputSchemas;
+
+/// Prepared instances of one template version.
+///
+/// Compiling needs exclusive access to a template instance. Each request checks out one
+/// instance, so a pool with several instances compiles that many requests in parallel.
+pub struct TemplatePool {
+    manifest: TemplateManifest,
+    schemas: InputSchemas,
+    instances: Mutex<Vec<Template<PackedTemplate>>>,
+    size: usize,
+    available: Arc<Semaphore>,
+    max_queue: usize,
+    waiting: AtomicUsize,
+    checkouts: AtomicU64,
+    rejected: AtomicU64,
+    total_wait_micros: AtomicU64,
+    max_wait_micros: AtomicU64,
+}
+
+/// All instances are in use and the queue of waiting requests is full.
+#[derive(Debug)]
+pub struct PoolExhausted;
+
+/// Usage statistics of a template pool.
+pub struct PoolStats {
+    /// Number of instances in the pool.
+    pub size: usize,
+    /// Instances that are currently not checked out.
+    pub available: usize,
+    /// Requests currently waiting for an instance.
+    pub waiting: usize,
+    /// Successful checkouts since the pool was created.
+    pub checkouts: u64,
+    /// Requests rejected because the queue was full.
+    pub rejected: u64,
+    /// Sum of the time all checkouts waited for an instance.
+    pub total_wait: Duration,
+    /// Longest time a checkout waited for an instance.
+    pub max_wait: Duration,
+}
+
+impl TemplatePool {
+    /// Create a pool from prepared instances of the same template.
+    ///
+    /// At most `max_queue` requests wait for an instance, further requests are rejected.
+    pub fn new(instances: Vec<Template<PackedTemplate>>, max_queue: usize) -> Self {
+        let first = instances
+            .first()
+            .expect("A template pool needs at least one instance");
+        let manifest = first.manifest().clone();
+        let schemas = InputSchemas::load(first);
+        let size = instances.len();
+
+        TemplatePool {
+            manifest,
+            schemas,
+            instances: Mutex::new(instances),
+            size,
+            available: Arc::new(Semaphore::new(size)),
+            max_queue,
+            waiting: AtomicUsize::new(0),
+            checkouts: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            total_wait_micros: AtomicU64::new(0),
+            max_wait_micros: AtomicU64::new(0),
+        }
+    }
+
+    /// The manifest of the pooled template.
+    pub fn manifest(&self) -> &TemplateManifest {
+        &self.manifest
+    }
+
+    /// The schemas of the template's JSON inputs.
+    pub fn schemas(&self) -> &InputSchemas {
+        &self.schemas
+    }
+
+    /// Wait for a free instance of the template.
+    ///
+    /// The instance returns to the pool when the returned guard is dropped.
+    pub async fn checkout(self: &Arc<Self>) -> Result<PooledTemplate, PoolExhausted> {
+        let start = Instant::now();
+        let permit = match self.available.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let _waiting = WaitingGuard::enter(self)?;
+                self.available
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("The pool semaphore is never closed")
+            }
+        };
+
+        let wait = u64::try_from(start.elapsed().as_micros()).unwrap_or(u64::MAX);
+        self.checkouts.fetch_add(1, Ordering::Relaxed);
+        self.total_wait_micros.fetch_add(wait, Ordering::Relaxed);
+        self.max_wait_micros.fetch_max(wait, Ordering::Relaxed);
+
+        let template = self
+            .instances
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .pop()
+            .expect("A permit guarantees a free instance");
+
+        Ok(PooledTemplate {
+            template: Some(template),
+            pool: self.clone(),
+            _permit: permit,
+        })
+    }
+
+    /// Current usage statistics of the pool.
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            size: self.size,
+            available: self.available.available_permits(),
+            waiting: self.waiting.load(Ordering::Relaxed),
+            checkouts: self.checkouts.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            total_wait: Duration::from_micros(self.total_wait_micros.load(Ordering::Relaxed)),
+            max_wait: Duration::from_micros(self.max_wait_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Counts a request as waiting for an instance until it is dropped.
+struct WaitingGuard<'a>(&'a AtomicUsize);
+
+impl<'a> WaitingGuard<'a> {
+    fn enter(pool: &'a TemplatePool) -> Result<Self, PoolExhausted> {
+        if pool.waiting.fetch_add(1, Ordering::SeqCst) >= pool.max_queue {
+            pool.waiting.fetch_sub(1, Ordering::SeqCst);
+            pool.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(PoolExhausted);
+        }
+
+        Ok(WaitingGuard(&pool.waiting))
+    }
+}
+
+impl Drop for WaitingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A template instance checked out from a pool.
+pub struct PooledTemplate {
+    template: Option<Template<PackedTemplate>>,
+    pool: Arc<TemplatePool>,
+    // Released after the instance is back in the pool
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Deref for PooledTemplate {
+    type Target = Template<PackedTemplate>;
+
+    fn deref(&self) -> &Self::Target {
+        self.template
+            .as_ref()
+            .expect("The template is only taken on drop")
+    }
+}
+
+impl DerefMut for PooledTemplate {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.template
+            .as_mut()
+            .expect("The template is only taken on drop")
+    }
+}
+
+impl Drop for PooledTemplate {
+    fn drop(&mut self) {
+        if let Some(template) = self.template.take() {
+            self.pool
+                .instances
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .push(template);
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use axum::http::{HeaderMap, header};
+use serde::Deserialize;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::{
+    image::{ImageOptions, accepts_multipart, png_options, svg_options},
+    pages::{PageSelection, parse_pages},
+};
+
+/// Output format of a render request
+#[derive(ToSchema, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum RenderFormat {
+    Pdf,
+    Png,
+    Svg,
+    /// Only compile the template and report its page count and warnings
+    Json,
+}
+
+/// Query parameters of render requests
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RenderQuery {
+    /// Output format. Takes precedence over the `Accept` header. Without either, the
+    /// template is rendered as PDF.
+    format: Option<RenderFormat>,
+    /// PNG only: pixels per point, at most 10. Defaults to 1.
+    #[param(example = 2.0)]
+    scale: Option<f32>,
+    /// PNG only: resolution in dots per inch, as an alternative to `scale`.
+    #[param(example = 144.0)]
+    dpi: Option<f32>,
+    /// Pages to render, like `1` or `1,3-5`. Defaults to all pages. PDFs keep the order of
+    /// the pages in the document. Pages can only be selected once. Not used for JSON reports.
+    #[param(example = "1")]
+    pages: Option<String>,
+    /// PNG and SVG only: render all selected pages into one image, or each page into its
+    /// own image. Separate images are returned as a zip archive, or as `multipart/mixed`
+    /// if the request accepts it. Defaults to `true`.
+    #[param(example = false)]
+    merge: Option<bool>,
+}
+
+/// What to produce from a compiled template
+pub enum RenderOutput {
+    Pdf {
+        pages: Option<PageSelection>,
+    },
+    Images {
+        options: ImageOptions,
+        multipart: bool,
+    },
+    /// Page count and warnings as JSON
+    Report,
+}
+
+/// A render request that cannot be served
+pub enum RenderRequestError {
+    /// The `Accept` header lists no supported type
+    NotAcceptable(String),
+    Invalid(String),
+}
+
+/// Media ranges that select PDF
+const PDF_TYPES: [&str; 3] = ["application/pdf", "application/*", "*/*"];
+
+/// Media types that do not select a format, but are accepted in addition to one.
+///
+/// Separate pages come as zip or `multipart/mixed` and failures can be rendered as text.
+/// Requests accepting only these get the default format, PDF.
+const AUXILIARY_TYPES: [&str; 3] = ["multipart/mixed", "application/zip", "text/plain"];
+
+impl RenderQuery {
+    /// Decide what to render based on the query and the headers of the request.
+    pub fn output(self, headers: &HeaderMap) -> Result<RenderOutput, RenderRequestError> {
+        let format = match self.format {
+            Some(format) => format,
+            None => negotiate(headers)?,
+        };
+
+        match format {
+            RenderFormat::Pdf => Ok(RenderOutput::Pdf {
+                pages: parse_pages(self.pages).map_err(RenderRequestError::Invalid)?,
+            }),
+            RenderFormat::Json => Ok(RenderOutput::Report),
+            RenderFormat::Png => Ok(RenderOutput::Images {
+                options: png_options(self.scale, self.dpi, self.pages, self.merge)
+                    .map_err(RenderRequestError::Invalid)?,
+                multipart: accepts_multipart(headers),
+            }),
+            RenderFormat::Svg => Ok(RenderOutput::Images {
+                options: svg_options(self.pages, self.merge)
+                    .map_err(RenderRequestError::Invalid)?,
+                multipart: accepts_multipart(headers),
+            }),
+        }
+    }
+}
+
+/// Pick the format from the `Accept` header, preferring media ranges with a higher quality.
+fn negotiate(headers: &HeaderMap) -> Result<RenderFormat, RenderRequestError> {
+    let accept: Vec<&str> = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|accept| accept.to_str().ok())
+        .collect();
+    let (ranges, excluded) = media_ranges(&accept);
+    // PDF is the fallback, unless the request rules it out explicitly
+    let fallback = if excluded
+        .iter()
+        .any(|(media_type, _)| PDF_TYPES.contains(media_type))
+    {
+        Err(RenderRequestError::NotAcceptable(accept.join(", ")))
+    } else {
+        Ok(RenderFormat::Pdf)
+    };
+    if ranges.is_empty() {
+        return fallback;
+    }
+
+    let format = ranges.iter().find_map(|(media_type, _)| match *media_type {
+        media_type if PDF_TYPES.contains(&media_type) => Some(RenderFormat::Pdf),
+        "image/png" | "image/*" => Some(RenderFormat::Png),
+        "image/svg+xml" => Some(RenderFormat::Svg),
+        "application/json" => Some(RenderFormat::Json),
+        _ => None,
+    });
+    match format {
+        Some(format) => Ok(format),
+        None if ranges
+            .iter()
+            .all(|(media_type, _)| AUXILIARY_TYPES.contains(media_type)) =>
+        {
+            fallback
+        }
+        None => Err(RenderRequestError::NotAcceptable(accept.join(", "))),
+    }
+}
+
+/// A media range of an `Accept` header with its quality
+pub type MediaRange<'a> = (&'a str, f32);
+
+/// Media ranges of `Accept` headers, ordered by their quality.
+///
+/// Ranges of equal quality keep the order of the headers. Ranges that are ruled out with
+/// `q=0` are returned separately.
+pub fn media_ranges<'a>(accept: &[&'a str]) -> (Vec<MediaRange<'a>>, Vec<MediaRange<'a>>) {
+    let (mut ranges, excluded): (Vec<_>, Vec<_>) = accept
+        .iter()
+        .flat_map(|accept| accept.split(','))
+        .filter_map(|range| {
+            let mut parameters = range.split(';');
+            let media_type = parameters.next()?.trim();
+            let quality = parameters
+                .filter_map(|parameter| parameter.trim().strip_prefix("q="))
+                .find_map(|quality| quality.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            (!media_type.is_empty()).then_some((media_type, quality))
+        })
+        .partition(|(_, quality): &MediaRange| *quality > 0.0);
+    // Stable, so ranges of equal quality keep their order
+    ranges.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+    (ranges, excluded)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn format(accept: &[&str]) -> Result<RenderFormat, String> {
+        let mut headers = HeaderMap::new();
+        for value in accept {
+            headers.append(header::ACCEPT, HeaderValue::from_str(value).unwrap());
+        }
+        negotiate(&headers).map_err(|error| match error {
+            RenderRequestError::NotAcceptable(accept) => accept,
+            RenderRequestError::Invalid(error) => panic!("unexpected error: {error}"),
+        })
+    }
+
+    #[test]
+    fn defaults_to_pdf() {
+        assert_eq!(format(&[]), Ok(RenderFormat::Pdf));
+        assert_eq!(format(&["*/*"]), Ok(RenderFormat::Pdf));
+        assert_eq!(format(&["application/*"]), Ok(RenderFormat::Pdf));
+    }
+
+    #[test]
+    fn picks_the_format_of_the_media_type() {
+        assert_eq!(format(&["application/pdf"]), Ok(RenderFormat::Pdf));
+        assert_eq!(format(&["image/png"]), Ok(RenderFormat::Png));
+        assert_eq!(format(&["image/*"]), Ok(RenderFormat::Png));
+        assert_eq!(format(&["image/svg+xml"]), Ok(RenderFormat::Svg));
+        assert_eq!(format(&["application/json"]), Ok(RenderFormat::Json));
+    }
+
+    #[test]
+    fn prefers_higher_quality() {
+        assert_eq!(
+            format(&["application/pdf;q=0.5, image/png"]),
+            Ok(RenderFormat::Png)
+        );
+        assert_eq!(
+            format(&["image/svg+xml; q=0.9", "application/json; q=0.8"]),
+            Ok(RenderFormat::Svg)
+        );
+        // Equal quality keeps the order of the header
+        assert_eq!(format(&["image/svg+xml, image/png"]), Ok(RenderFormat::Svg));
+    }
+
+    #[test]
+    fn ignores_excluded_and_unknown_types() {
+        assert_eq!(
+            format(&["image/png;q=0, application/json"]),
+            Ok(RenderFormat::Json)
+        );
+        assert_eq!(
+            format(&["text/html, image/png;q=0.1"]),
+            Ok(RenderFormat::Png)
+        );
+    }
+
+    #[test]
+    fn renders_pdf_if_only_auxiliary_types_are_accepted() {
+        assert_eq!(format(&["text/plain"]), Ok(RenderFormat::Pdf));
+        assert_eq!(format(&["application/zip"]), Ok(RenderFormat::Pdf));
+        assert_eq!(
+            format(&["multipart/mixed, application/zip", "text/plain"]),
+            Ok(RenderFormat::Pdf)
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_types() {
+        assert_eq!(format(&["text/html"]), Err("text/html".to_owned()));
+        assert_eq!(
+            format(&["text/plain, text/html"]),
+            Err("text/plain, text/html".to_owned())
+        );
+        assert_eq!(
+            format(&["text/html", "application/xml"]),
+            Err("text/html, application/xml".to_owned())
+        );
+    }
+
+    #[test]
+    fn falls_back_to_pdf_only_if_it_is_not_excluded() {
+        assert_eq!(
+            format(&["application/pdf;q=0"]),
+            Err("application/pdf;q=0".to_owned())
+        );
+        assert_eq!(
+            format(&["*/*;q=0, text/plain"]),
+            Err("*/*;q=0, text/plain".to_owned())
+        );
+        assert_eq!(
+            format(&["application/pdf;q=0, image/png"]),
+            Ok(RenderFormat::Png)
+        );
+        assert_eq!(format(&["image/png;q=0"]), Ok(RenderFormat::Pdf));
+    }
+}
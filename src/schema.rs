@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+
+use jsonschema::Validator;
+use oicana::Template;
+use oicana_files::packed::PackedTemplate;
+use oicana_input::input_definition::InputDefinition;
+use serde::Serialize;
+use tracing::warn;
+use typst::syntax::{FileId, VirtualPath};
+use utoipa::ToSchema;
+
+/// JSON schemas of the inputs of a template.
+pub struct InputSchemas {
+    schemas: HashMap<String, InputSchema>,
+}
+
+struct InputSchema {
+    schema: serde_json::Value,
+    validator: Validator,
+}
+
+/// A part of a JSON input that does not match the schema of the input
+#[derive(ToSchema, Serialize)]
+pub struct SchemaViolation {
+    /// Key of the input
+    #[schema(example = "invoice")]
+    pub input: String,
+    /// JSON pointer to the invalid value inside the input
+    #[schema(example = "/items/0/price")]
+    pub path: String,
+    #[schema(example = "\"ten\" is not of type \"number\"")]
+    pub message: String,
+}
+
+impl InputSchemas {
+    /// Load the schemas that the manifest of a template declares for its JSON inputs.
+    ///
+    /// Schemas that are missing or invalid are logged and skipped. Inputs without a schema
+    /// are not validated.
+    pub fn load(template: &Template<PackedTemplate>) -> Self {
+        let manifest = template.manifest();
+        let template_id = &manifest.package.name;
+        let mut schemas = HashMap::new();
+
+        for definition in &manifest.tool.oicana.inputs {
+            let InputDefinition::Json(input) = definition else {
+                continue;
+            };
+            let Some(path) = &input.schema else {
+                continue;
+            };
+
+            match read_schema(template, path) {
+                Ok(schema) => {
+                    schemas.insert(input.key.clone(), schema);
+                }
+                Err(error) => warn!(
+                    %template_id,
+                    "Input '{}' of template '{template_id}' is not validated, its schema '{path}' is unusable: {error}",
+                    input.key
+                ),
+            }
+        }
+
+        InputSchemas { schemas }
+    }
+
+    /// The schema of a JSON input, if it has a usable one.
+    pub fn get(&self, key: &str) -> Option<&serde_json::Value> {
+        self.schemas.get(key).map(|input| &input.schema)
+    }
+
+    /// Check JSON inputs against their schemas and return every violation.
+    pub fn validate<'a>(
+        &self,
+        inputs: impl IntoIterator<Item = (&'a str, &'a serde_json::Value)>,
+    ) -> Vec<SchemaViolation> {
+        inputs
+            .into_iter()
+            .filter_map(|(key, value)| Some((key, value, self.schemas.get(key)?)))
+            .flat_map(|(key, value, input)| {
+                input
+                    .validator
+                    .iter_errors(value)
+                    .map(move |error| SchemaViolation {
+                        input: key.to_owned(),
+                        path: error.instance_path().to_string(),
+                        message: error.to_string(),
+                    })
+            })
+            .collect()
+    }
+}
+
+/// Read and compile a schema file of the template.
+fn read_schema(template: &Template<PackedTemplate>, path: &str) -> Result<InputSchema, String> {
+    let bytes = read_file(template, path)?;
+    let schema: serde_json::Value =
+        serde_json::from_slice(&bytes).map_err(|error| error.to_string())?;
+    let validator = jsonschema::validator_for(&schema).map_err(|error| error.to_string())?;
+
+    Ok(InputSchema { schema, validator })
+}
+
+/// Read a file of the template by its path relative to the template root.
+pub fn read_file(template: &Template<PackedTemplate>, path: &str) -> Result<Vec<u8>, String> {
+    template
+        .file(FileId::new(None, VirtualPath::new(path)))
+        .map(|bytes| bytes.to_vec())
+        .map_err(|error| error.to_string())
+}
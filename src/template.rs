@@ -1,103 +1,357 @@
-use std::{fs::File, sync::Arc};
+use std::{sync::Arc, time::Duration};
 
 use axum::{
     Json,
-    body::Body,
-    extract::{Path, State},
-    http::{StatusCode, header},
+    http::StatusCode,
     response::{IntoResponse, Response},
 };
-use dashmap::DashMap;
-use oicana::Template;
-use oicana_export::{pdf::export_merged_pdf, png::export_merged_png};
-use oicana_files::packed::PackedTemplate;
 use oicana_input::{
-    CompilationConfig, TemplateInputs, input::blob::BlobInput as OicanaBlobInput,
-    input::json::JsonInput as OicanaJsonInput,
+    CompilationConfig, CompilationMode, TemplateInputs, input::blob::BlobInput as OicanaBlobInput,
+    input::json::JsonInput as OicanaJsonInput, input_definition::InputDefinition,
 };
-use oicana_world::{TemplateCompilationFailure, diagnostics::DiagnosticColor};
+use oicana_world::TemplateCompilationFailure;
+use semver::Version;
 use serde::{Deserialize, Serialize};
-use tokio_util::io::ReaderStream;
-use tracing::{error, info};
-use utoipa::ToSchema;
-use utoipa_axum::router::OpenApiRouter;
-use utoipa_axum::routes;
+use tokio_util::sync::CancellationToken;
+use utoipa::{
+    ToSchema,
+    openapi::path::{Operation, PathItem},
+};
+use utoipa_axum::router::{OpenApiRouter, UtoipaMethodRouter};
 use uuid::Uuid;
 
-use crate::blob::{BlobStorage, get_blob};
-
-const TEMPLATES: &[(&str, &str)] = &[
-    ("accessibility", "0.1.0"),
-    ("certificate", "0.1.0"),
-    ("dependency", "0.1.0"),
-    ("fonts", "0.1.0"),
-    ("invoice", "0.1.0"),
-    ("invoice_zugferd", "0.1.0"),
-    ("minimal", "0.1.0"),
-    ("table", "0.1.0"),
-    ("multi_input", "0.1.0"),
-];
+use crate::{
+    blob::{BlobStorage, get_blob},
+    cache::{CachedTemplate, TemplateCache, TemplateKey},
+    diagnostics::{failure_response, with_plain_text},
+    job::{JobState, JobStore},
+    pages::PageOutOfRange,
+    pool::{PoolExhausted, PooledTemplate, TemplatePool},
+    schema::SchemaViolation,
+    warnings::WarningStore,
+    worker::{CompilePool, JobPanicked},
+};
 
-type TemplateCache = Arc<DashMap<String, Template<PackedTemplate>>>;
+mod batch;
+mod info;
+mod jobs;
+mod merge;
+mod render;
+mod upload;
+mod validate;
 
 #[derive(Clone)]
 struct AppState {
-    template_cache: TemplateCache,
+    template_cache: Arc<TemplateCache>,
+    compile_pool: Arc<CompilePool>,
     blob_storage: BlobStorage,
+    warnings: Arc<WarningStore>,
+    jobs: Arc<JobStore>,
+    default_mode: CompilationMode,
 }
 
-/// Create the template router with all template-related endpoints
-pub fn router(blob_storage: BlobStorage, template_cache: TemplateCache) -> OpenApiRouter {
+/// Version path segment that selects the highest available version of a template.
+const LATEST: &str = "latest";
+
+impl AppState {
+    /// Find the requested version of a template. `latest` selects the highest available version.
+    fn resolve(&self, id: String, version: &str) -> Result<TemplateKey, TemplateError> {
+        let requested = match version {
+            LATEST => None,
+            version => match Version::parse(version) {
+                Ok(version) => Some(version),
+                Err(error) => {
+                    return Err(TemplateError::InvalidVersion {
+                        id,
+                        version: version.to_owned(),
+                        error: error.to_string(),
+                    });
+                }
+            },
+        };
+
+        match (
+            self.template_cache.resolve(&id, requested.as_ref()),
+            requested,
+        ) {
+            (Some(key), _) => Ok(key),
+            (None, Some(version)) => Err(TemplateError::VersionNotFound { id, version }),
+            (None, None) => Err(TemplateError::NotFound(id)),
+        }
+    }
+
+    /// Get the instances of a template, loading the template if necessary.
+    async fn template_pool(&self, key: &TemplateKey) -> Result<CachedTemplate, TemplateError> {
+        self.template_cache
+            .get(key)
+            .await
+            .ok_or_else(|| TemplateError::NotFound(key.id.clone()))
+    }
+
+    /// Check out an instance of a template.
+    async fn checkout(
+        &self,
+        id: &str,
+        pool: &CachedTemplate,
+    ) -> Result<PooledTemplate, TemplateError> {
+        pool.checkout()
+            .await
+            .map_err(|PoolExhausted| TemplateError::Busy(id.to_owned()))
+    }
+
+    /// Collect the inputs of a compilation request.
+    ///
+    /// Fails if the request lacks an input that the template has no value for in the
+    /// requested mode.
+    fn template_inputs(
+        &self,
+        id: &str,
+        pool: &TemplatePool,
+        payload: CompilationPayload,
+    ) -> Result<TemplateInputs, TemplateError> {
+        let manifest = pool.manifest();
+        let mode = payload
+            .mode
+            .map_or(self.default_mode, CompilationMode::from);
+        let missing: Vec<String> = manifest
+            .tool
+            .oicana
+            .inputs
+            .iter()
+            .filter_map(|definition| {
+                let (key, has_default, has_development) = match definition {
+                    InputDefinition::Json(input) => {
+                        let provided = payload.json_inputs.iter().any(|json| json.key == input.key);
+                        (
+                            &input.key,
+                            provided || input.default.is_some(),
+                            input.development.is_some(),
+                        )
+                    }
+                    InputDefinition::Blob(input) => {
+                        let provided = payload.blob_inputs.iter().any(|blob| blob.key == input.key);
+                        (
+                            &input.key,
+                            provided || input.default.is_some(),
+                            input.development.is_some(),
+                        )
+                    }
+                };
+                let available =
+                    has_default || (mode == CompilationMode::Development && has_development);
+                (!available).then(|| key.clone())
+            })
+            .collect();
+        if !missing.is_empty() {
+            return Err(TemplateError::MissingInputs {
+                id: id.to_owned(),
+                mode,
+                keys: missing,
+            });
+        }
+
+        let violations = pool.schemas().validate(
+            payload
+                .json_inputs
+                .iter()
+                .map(|input| (input.key.as_str(), &input.value)),
+        );
+        if !violations.is_empty() {
+            return Err(TemplateError::InvalidInputs {
+                id: id.to_owned(),
+                violations,
+            });
+        }
+
+        let mut inputs = TemplateInputs::new();
+        inputs.with_config(CompilationConfig::new(mode));
+
+        for JsonInput { key, value } in payload.json_inputs {
+            inputs.with_input(OicanaJsonInput::new(key, value.to_string()));
+        }
+
+        for BlobInput { key, blob_id } in payload.blob_inputs {
+            if let Some(data) = get_blob(&self.blob_storage, blob_id) {
+                inputs.with_input(OicanaBlobInput::new(key, data));
+            } else {
+                return Err(TemplateError::BlobNotFound {
+                    template_id: id.to_owned(),
+                    blob_id,
+                });
+            }
+        }
+
+        Ok(inputs)
+    }
+
+    /// Run a job for the given template on the compile threads.
+    async fn run<T, F>(&self, id: &str, job: F) -> Result<T, TemplateError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&CancellationToken) -> Result<T, TemplateError> + Send + 'static,
+    {
+        self.compile_pool
+            .run(job)
+            .await
+            .unwrap_or_else(|JobPanicked| Err(TemplateError::Aborted(id.to_owned())))
+    }
+}
+
+/// Create the router with all template-related endpoints under `/templates`, the render jobs
+/// under `/jobs` and merged PDFs under `/merge`
+pub fn router(
+    blob_storage: BlobStorage,
+    template_cache: Arc<TemplateCache>,
+    compile_pool: Arc<CompilePool>,
+    warnings: Arc<WarningStore>,
+    jobs: Arc<JobStore>,
+    default_mode: CompilationMode,
+) -> OpenApiRouter {
     let state = AppState {
         template_cache,
+        compile_pool,
         blob_storage,
+        warnings,
+        jobs,
+        default_mode,
     };
 
+    let templates = OpenApiRouter::new()
+        .merge(render::router())
+        .merge(validate::router())
+        .merge(batch::router())
+        .merge(info::router())
+        .merge(upload::router());
+
     OpenApiRouter::new()
-        .routes(routes!(compile_template))
-        .routes(routes!(preview_template))
-        .routes(routes!(reset_template))
-        .routes(routes!(get_template))
-        .routes(routes!(get_template_list))
+        .nest("/templates", templates)
+        .nest("/jobs", jobs::router())
+        .nest("/merge", merge::router())
         .with_state(state)
 }
 
-/// Load and cache all templates.
-/// This method expects templates to compile in development mode without extra inputs.
-pub fn warmed_up_templates() -> DashMap<String, Template<PackedTemplate>> {
-    let cache = DashMap::new();
+/// Template version selected by the path of a request
+///
+/// Routes without a version segment select the latest version.
+#[derive(Deserialize)]
+struct TemplatePath {
+    #[serde(rename = "template_id")]
+    id: String,
+    #[serde(default = "latest")]
+    version: String,
+}
+
+fn latest() -> String {
+    LATEST.to_owned()
+}
 
-    for (id, version) in TEMPLATES {
-        let template_file = match File::open(format!("templates/{id}-{version}.zip")) {
-            Ok(file) => file,
-            Err(error) => {
-                error!("'templates/{id}-{version}.zip' not found during warm-up: {error:?}");
-                continue;
+/// Path segment of the routes that select a version of a template
+const VERSION_SEGMENT: &str = "/versions/{version}";
+
+/// Serve a route documented for a template version for the latest version as well.
+///
+/// Handlers of such routes take a [`TemplatePath`] and are documented once, with a
+/// `/versions/{version}` segment in their path. The route for the latest version drops that
+/// segment and the `version` parameter. It keeps the operation ID of the handler, while the
+/// versioned operation gets a `_version` suffix.
+fn with_latest<S>(
+    (schemas, mut paths, method_router): UtoipaMethodRouter<S>,
+) -> UtoipaMethodRouter<S> {
+    let versioned = std::mem::take(&mut paths.paths);
+    for (path, mut item) in versioned {
+        let mut latest = item.clone();
+        for operation in operations(&mut latest) {
+            if let Some(parameters) = &mut operation.parameters {
+                parameters.retain(|parameter| parameter.name != "version");
             }
-        };
-        let mut template = match Template::init(template_file) {
-            Ok(template) => template,
-            Err(error) => {
-                error!(
-                    "'templates/{id}-{version}.zip' failed to compile during warm-up: {error:?}"
-                );
-                continue;
+            if let Some(description) = &mut operation.description {
+                description.push_str(" This route uses the latest version of the template.");
             }
-        };
-        template.set_diagnostic_color(DiagnosticColor::None);
-        info!("Warmed-up {id} v{version}.");
-        cache.insert(id.to_string(), template);
+        }
+        for operation in operations(&mut item) {
+            if let Some(id) = &mut operation.operation_id {
+                id.push_str("_version");
+            }
+        }
+
+        paths
+            .paths
+            .insert(path.replace(VERSION_SEGMENT, ""), latest);
+        paths.paths.insert(path, item);
     }
 
-    cache
+    (schemas, paths, method_router)
 }
 
+/// All operations of a documented path.
+fn operations(item: &mut PathItem) -> impl Iterator<Item = &mut Operation> {
+    [
+        &mut item.get,
+        &mut item.put,
+        &mut item.post,
+        &mut item.delete,
+        &mut item.patch,
+    ]
+    .into_iter()
+    .flatten()
+}
+
+/// Description of the request body of compilations
+const PAYLOAD_DESCRIPTION: &str = "Inputs and config for template compilation";
+/// Description of the `template_id` path parameter
+const TEMPLATE_ID_DESCRIPTION: &str = "The identifier of the template.";
+/// Description of the `version` path parameter
+const VERSION_DESCRIPTION: &str = "The version of the template, or `latest`.";
+/// Description of successful responses that link to the warnings of their compilation
+const WARNINGS_DESCRIPTION: &str = "Success. If the compilation produced warnings, the `x-compilation-warnings` header holds their number and the `link` header points to them.";
+/// Description of failed compilations to PDF
+const PDF_FAILURE_DESCRIPTION: &str = "The template failed to compile with the given inputs, the selected pages do not exist, or the requested PDF standards cannot be combined with those of the template. Request `text/plain` for the rendered diagnostics.";
+/// Description of failed compilations to images
+const IMAGE_FAILURE_DESCRIPTION: &str = "The template failed to compile with the given inputs, or the selected pages do not exist. Request `text/plain` for the rendered diagnostics.";
+/// Description of inputs that do not match their schemas
+const INVALID_INPUTS_DESCRIPTION: &str = "JSON inputs do not match the schemas declared in the template's manifest. Request `text/plain` for a list of the mismatches.";
+
 enum TemplateError {
     NotFound(String),
+    VersionNotFound {
+        id: String,
+        version: Version,
+    },
+    InvalidVersion {
+        id: String,
+        version: String,
+        error: String,
+    },
     BlobNotFound {
         template_id: String,
         blob_id: Uuid,
     },
+    MissingInputs {
+        id: String,
+        mode: CompilationMode,
+        keys: Vec<String>,
+    },
+    InvalidInputs {
+        id: String,
+        violations: Vec<SchemaViolation>,
+    },
+    InputNotFound {
+        id: String,
+        key: String,
+    },
+    NoInputSchema {
+        id: String,
+        key: String,
+    },
+    NoInputExample {
+        id: String,
+        key: String,
+    },
+    UnreadableInputFile {
+        id: String,
+        key: String,
+        error: String,
+    },
     CompilationFailure {
         id: String,
         error: TemplateCompilationFailure,
@@ -106,6 +360,61 @@ enum TemplateError {
         id: String,
         error: String,
     },
+    Aborted(String),
+    Cancelled(String),
+    Busy(String),
+    WarningsNotFound {
+        id: String,
+        warnings_id: Uuid,
+    },
+    InvalidRenderOptions {
+        id: String,
+        error: String,
+    },
+    NotAcceptable {
+        id: String,
+        accept: String,
+    },
+    PageOutOfRange {
+        id: String,
+        error: PageOutOfRange,
+    },
+    InvalidPdfStandards {
+        id: String,
+        error: String,
+    },
+    InvalidCallback {
+        id: String,
+        error: String,
+    },
+    InvalidMerge(String),
+    MergeFailure(String),
+    JobQueueFull(String),
+    JobNotFound {
+        job_id: Uuid,
+        retention: Duration,
+    },
+    JobNotFinished {
+        job_id: Uuid,
+        job_state: JobState,
+    },
+    InvalidUpload {
+        id: String,
+        error: String,
+    },
+    StorageFailure {
+        id: String,
+        error: String,
+    },
+}
+
+/// JSON inputs that do not match the schemas of the template
+#[derive(ToSchema, Serialize)]
+struct InvalidInputsResponse {
+    #[schema(example = "Inputs of template 'invoice' do not match their schemas!")]
+    message: String,
+    /// Every value that does not match its schema
+    errors: Vec<SchemaViolation>,
 }
 
 impl IntoResponse for TemplateError {
@@ -123,6 +432,29 @@ impl IntoResponse for TemplateError {
                     format!("Template '{template_id}' not found!"),
                 )
             }
+            TemplateError::VersionNotFound {
+                id: template_id,
+                version,
+            } => {
+                tracing::error!(%template_id, %version, "Template '{template_id}' v{version} not found!");
+                (
+                    StatusCode::NOT_FOUND,
+                    format!("Template '{template_id}' v{version} not found!"),
+                )
+            }
+            TemplateError::InvalidVersion {
+                id: template_id,
+                version,
+                error,
+            } => {
+                tracing::error!(%template_id, %version, "Invalid version '{version}' for template '{template_id}': {error}");
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "'{version}' is not a valid version ({error}). Use a semantic version like '0.1.0' or '{LATEST}'."
+                    ),
+                )
+            }
             TemplateError::BlobNotFound {
                 template_id,
                 blob_id,
@@ -135,6 +467,102 @@ impl IntoResponse for TemplateError {
                     ),
                 )
             }
+            TemplateError::MissingInputs {
+                id: template_id,
+                mode,
+                keys,
+            } => {
+                let mode = match mode {
+                    CompilationMode::Production => "production",
+                    CompilationMode::Development => "development",
+                };
+                let count = keys.len();
+                let keys = keys
+                    .iter()
+                    .map(|key| format!("'{key}'"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                tracing::error!(%template_id, "Template '{template_id}' is missing inputs {keys} in {mode} mode");
+                let message = if count > 1 {
+                    format!(
+                        "Template '{template_id}' requires the inputs {keys} in {mode} mode. They have no default value in the template."
+                    )
+                } else {
+                    format!(
+                        "Template '{template_id}' requires the input {keys} in {mode} mode. It has no default value in the template."
+                    )
+                };
+                (StatusCode::BAD_REQUEST, message)
+            }
+            TemplateError::InvalidInputs {
+                id: template_id,
+                violations,
+            } => {
+                tracing::error!(%template_id, "Inputs of template '{template_id}' do not match their schemas in {} places", violations.len());
+                let message =
+                    format!("Inputs of template '{template_id}' do not match their schemas!");
+                let text = violations.iter().fold(message.clone(), |text, violation| {
+                    format!(
+                        "{text}\n  {}{}: {}",
+                        violation.input, violation.path, violation.message
+                    )
+                });
+                let body = InvalidInputsResponse {
+                    message,
+                    errors: violations,
+                };
+                return with_plain_text(
+                    (StatusCode::UNPROCESSABLE_ENTITY, Json(body)).into_response(),
+                    text,
+                );
+            }
+            TemplateError::InputNotFound {
+                id: template_id,
+                key,
+            } => {
+                tracing::error!(%template_id, "Template '{template_id}' has no input '{key}'");
+                (
+                    StatusCode::NOT_FOUND,
+                    format!("Template '{template_id}' has no input '{key}'!"),
+                )
+            }
+            TemplateError::NoInputSchema {
+                id: template_id,
+                key,
+            } => {
+                tracing::error!(%template_id, "Input '{key}' of template '{template_id}' has no schema");
+                (
+                    StatusCode::NOT_FOUND,
+                    format!(
+                        "Input '{key}' of template '{template_id}' has no usable JSON schema. Only JSON inputs can declare a schema in the manifest."
+                    ),
+                )
+            }
+            TemplateError::NoInputExample {
+                id: template_id,
+                key,
+            } => {
+                tracing::error!(%template_id, "Input '{key}' of template '{template_id}' has no example value");
+                (
+                    StatusCode::NOT_FOUND,
+                    format!(
+                        "Input '{key}' of template '{template_id}' has neither a default nor a development value!"
+                    ),
+                )
+            }
+            TemplateError::UnreadableInputFile {
+                id: template_id,
+                key,
+                error,
+            } => {
+                tracing::error!(%template_id, %error, "Failed to read the value of input '{key}' of template '{template_id}': {error}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!(
+                        "Failed to read the value of input '{key}' of template '{template_id}'!"
+                    ),
+                )
+            }
             TemplateError::CompilationFailure {
                 id: template_id,
                 error,
@@ -147,26 +575,170 @@ impl IntoResponse for TemplateError {
                         tracing::error!(%template_id, "Template '{template_id}' failed to compile with given inputs: {}", error.error)
                     }
                 }
+                return failure_response(
+                    StatusCode::BAD_REQUEST,
+                    format!("Template '{template_id}' failed to compile with given inputs"),
+                    error,
+                );
+            }
+            TemplateError::ExportFailure {
+                id: template_id,
+                error,
+            } => {
+                tracing::error!(%template_id, %error, "Template '{template_id}' failed to export: {error}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Template '{template_id}' failed to export!\n{error}"),
+                )
+            }
+            TemplateError::Aborted(template_id) => {
+                tracing::error!(%template_id, "Compilation of template '{template_id}' was aborted");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Compilation of template '{template_id}' was aborted!"),
+                )
+            }
+            TemplateError::Cancelled(template_id) => {
+                tracing::error!(%template_id, "Compilation of template '{template_id}' was cancelled");
+                (
+                    StatusCode::REQUEST_TIMEOUT,
+                    format!("Compilation of template '{template_id}' was cancelled!"),
+                )
+            }
+            TemplateError::Busy(template_id) => {
+                tracing::error!(%template_id, "Too many requests are waiting for template '{template_id}'");
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    format!(
+                        "Too many requests are waiting for template '{template_id}', try again later."
+                    ),
+                )
+            }
+            TemplateError::WarningsNotFound {
+                id: template_id,
+                warnings_id,
+            } => {
+                tracing::error!(%template_id, %warnings_id, "Warnings {warnings_id} of template '{template_id}' not found");
+                (
+                    StatusCode::NOT_FOUND,
+                    format!(
+                        "Warnings {warnings_id} of template '{template_id}' not found! Only the warnings of recent compilations are kept."
+                    ),
+                )
+            }
+            TemplateError::InvalidRenderOptions {
+                id: template_id,
+                error,
+            } => {
+                tracing::error!(%template_id, %error, "Invalid render options for template '{template_id}': {error}");
+                (StatusCode::BAD_REQUEST, error)
+            }
+            TemplateError::NotAcceptable {
+                id: template_id,
+                accept,
+            } => {
+                tracing::error!(%template_id, "Template '{template_id}' cannot be rendered as any of '{accept}'");
+                (
+                    StatusCode::NOT_ACCEPTABLE,
+                    format!(
+                        "Template '{template_id}' cannot be rendered as any of '{accept}'. Supported types are application/pdf, image/png, image/svg+xml and application/json."
+                    ),
+                )
+            }
+            TemplateError::PageOutOfRange {
+                id: template_id,
+                error,
+            } => {
+                tracing::error!(%template_id, "Selected pages of template '{template_id}' are out of range: {error}");
                 (
                     StatusCode::BAD_REQUEST,
+                    format!("Template '{template_id}' cannot render the selected pages: {error}."),
+                )
+            }
+            TemplateError::InvalidPdfStandards {
+                id: template_id,
+                error,
+            } => {
+                tracing::error!(%template_id, %error, "Invalid PDF standards for template '{template_id}': {error}");
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("Template '{template_id}' cannot be exported as requested: {error}"),
+                )
+            }
+            TemplateError::InvalidCallback {
+                id: template_id,
+                error,
+            } => {
+                tracing::error!(%template_id, %error, "Invalid callback for a job of template '{template_id}': {error}");
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid callback URL: {error}"),
+                )
+            }
+            TemplateError::InvalidMerge(error) => {
+                tracing::error!(%error, "Invalid merge request: {error}");
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("The parts cannot be merged: {error}"),
+                )
+            }
+            TemplateError::MergeFailure(error) => {
+                tracing::error!(%error, "Failed to export a merged PDF: {error}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to export the merged PDF!\n{error}"),
+                )
+            }
+            TemplateError::JobQueueFull(template_id) => {
+                tracing::error!(%template_id, "Too many jobs are queued to render template '{template_id}'");
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Too many jobs are queued, try again later.".to_owned(),
+                )
+            }
+            TemplateError::JobNotFound { job_id, retention } => {
+                tracing::error!(%job_id, "Job {job_id} not found");
+                (
+                    StatusCode::NOT_FOUND,
                     format!(
-                        "Template '{template_id}' failed to compile with given inputs: {}{}",
-                        error.error,
-                        error
-                            .warnings
-                            .map(|warning| format!("\n\n{warning}"))
-                            .unwrap_or(String::new())
+                        "Job {job_id} not found! Finished jobs are kept for {} seconds.",
+                        retention.as_secs()
                     ),
                 )
             }
-            TemplateError::ExportFailure {
+            TemplateError::JobNotFinished { job_id, job_state } => {
+                let job_state = match job_state {
+                    JobState::Queued => "queued",
+                    _ => "running",
+                };
+                tracing::error!(%job_id, "Result of job {job_id} requested while it is {job_state}");
+                (
+                    StatusCode::CONFLICT,
+                    format!(
+                        "Job {job_id} is still {job_state}. Its status at /jobs/{job_id} shows when it has finished."
+                    ),
+                )
+            }
+            TemplateError::InvalidUpload {
                 id: template_id,
                 error,
             } => {
-                tracing::error!(%template_id, %error, "Template '{template_id}' failed to export: {error}");
+                tracing::error!(%template_id, %error, "Rejected upload of template '{template_id}': {error}");
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "Upload of template '{template_id}' is not a valid packed template: {error}"
+                    ),
+                )
+            }
+            TemplateError::StorageFailure {
+                id: template_id,
+                error,
+            } => {
+                tracing::error!(%template_id, %error, "Failed to store template '{template_id}': {error}");
                 (
                     StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Template '{template_id}' failed to export!\n{error}"),
+                    format!("Failed to store template '{template_id}'!"),
                 )
             }
         };
@@ -175,216 +747,24 @@ impl IntoResponse for TemplateError {
     }
 }
 
-#[utoipa::path(
-    method(post),
-    tag = super::TEMPLATE_TAG,
-    path = "/{template_id}/compile",
-    params(("template_id" = String, example = "table", description = "The identifier of the template to compile.")),
-    request_body(content = CompilationPayload, description = "Inputs and config for template compilation", content_type = "application/json"),
-    description = "Compile a template with given inputs.",
-    responses(
-        (status = OK, description = "Success", content_type = "application/pdf")
-    )
-)]
-#[axum::debug_handler]
-async fn compile_template(
-    State(state): State<AppState>,
-    Path(id): Path<String>,
-    Json(payload): Json<CompilationPayload>,
-) -> impl IntoResponse {
-    let Some(mut template) = state.template_cache.get_mut(&id) else {
-        return Err(TemplateError::NotFound(id));
-    };
-
-    let mut inputs = TemplateInputs::new();
-    inputs.with_config(CompilationConfig::development());
-
-    for JsonInput { key, value } in payload.json_inputs {
-        inputs.with_input(OicanaJsonInput::new(key, value.to_string()));
-    }
-
-    for BlobInput { key, blob_id } in payload.blob_inputs {
-        if let Some(data) = get_blob(&state.blob_storage, blob_id) {
-            inputs.with_input(OicanaBlobInput::new(key, data));
-        } else {
-            return Err(TemplateError::BlobNotFound {
-                template_id: id,
-                blob_id,
-            });
-        }
-    }
-
-    let compilation_result = match template.compile(inputs) {
-        Ok(document) => document,
-        Err(error) => return Err(TemplateError::CompilationFailure { id, error }),
-    };
-
-    let pdf = match export_merged_pdf(
-        &compilation_result.document,
-        &*template,
-        &template.manifest().tool.oicana.export.pdf.standards,
-    ) {
-        Ok(pdf) => pdf,
-        Err(error) => return Err(TemplateError::ExportFailure { id, error }),
-    };
-    let body = Body::from(pdf);
-
-    let headers = [
-        (header::CONTENT_TYPE, "application/pdf".to_owned()),
-        (
-            header::CONTENT_DISPOSITION,
-            format!("attachment; filename=\"{id}.pdf\""),
-        ),
-    ];
-
-    Ok((headers, body))
-}
-
-#[utoipa::path(
-    method(post),
-    tag = super::TEMPLATE_TAG,
-    path = "/{template_id}/preview",
-    params(("template_id" = String, example = "table", description = "The identifier of the template to preview.")),
-    request_body(content = CompilationPayload, description = "Inputs and config for template compilation", content_type = "application/json"),
-    description = "Generate a PNG preview of the template with given inputs.",
-    responses(
-        (status = OK, description = "Success", content_type = "image/png")
-    )
-)]
-#[axum::debug_handler]
-async fn preview_template(
-    State(state): State<AppState>,
-    Path(id): Path<String>,
-    Json(payload): Json<CompilationPayload>,
-) -> impl IntoResponse {
-    let Some(mut template) = state.template_cache.get_mut(&id) else {
-        return Err(TemplateError::NotFound(id));
-    };
-
-    let mut inputs = TemplateInputs::new();
-    inputs.with_config(CompilationConfig::development());
-
-    for JsonInput { key, value } in payload.json_inputs {
-        inputs.with_input(OicanaJsonInput::new(key, value.to_string()));
-    }
-
-    for BlobInput { key, blob_id } in payload.blob_inputs {
-        if let Some(data) = get_blob(&state.blob_storage, blob_id) {
-            inputs.with_input(OicanaBlobInput::new(key, data));
-        } else {
-            return Err(TemplateError::BlobNotFound {
-                template_id: id,
-                blob_id,
-            });
-        }
-    }
-
-    let compilation_result = match template.compile(inputs) {
-        Ok(document) => document,
-        Err(error) => return Err(TemplateError::CompilationFailure { id, error }),
-    };
-
-    // Export all pages merged as PNG
-    let png = export_merged_png(&compilation_result.document, 1.0).unwrap();
-    let body = Body::from(png);
-
-    let headers = [
-        (header::CONTENT_TYPE, "image/png".to_owned()),
-        (
-            header::CONTENT_DISPOSITION,
-            format!("inline; filename=\"{id}.png\""),
-        ),
-    ];
-
-    Ok((headers, body))
-}
-
-#[utoipa::path(
-    method(post),
-    tag = super::TEMPLATE_TAG,
-    path = "/{template_id}/reset",
-    params(("template_id" = String, example = "table", description = "The identifier of the template to reset.")),
-    description = "Reset (remove) a template from the cache. The template will be reloaded on next use.",
-    responses(
-        (status = NO_CONTENT, description = "Template successfully removed from cache"),
-        (status = NOT_FOUND, description = "Template not found in cache")
-    )
-)]
-async fn reset_template(
-    State(state): State<AppState>,
-    Path(id): Path<String>,
-) -> impl IntoResponse {
-    match state.template_cache.remove(&id) {
-        Some(_) => {
-            info!("Template '{}' removed from cache", id);
-            StatusCode::NO_CONTENT
-        }
-        None => {
-            error!("Template '{}' not found in cache", id);
-            StatusCode::NOT_FOUND
-        }
-    }
-}
-
-#[utoipa::path(
-    method(get),
-    tag = super::TEMPLATE_TAG,
-    path = "/{template_id}",
-    params(("template_id" = String, example = "table", description = "The identifier of the template to download.")),
-    description = "Download a packed template.",
-    responses(
-        (status = OK, description = "Success", content_type = "application/zip")
-    )
-)]
-async fn get_template(Path(id): Path<String>) -> impl IntoResponse {
-    let file = match tokio::fs::File::open(format!("templates/{id}-0.1.0.zip")).await {
-        Ok(file) => file,
-        Err(err) => {
-            return Err((
-                StatusCode::NOT_FOUND,
-                format!("Template not found: {}", err),
-            ));
-        }
-    };
-
-    let stream = ReaderStream::new(file);
-    let body = Body::from_stream(stream);
-
-    let headers = [
-        (header::CONTENT_TYPE, "application/zip".to_owned()),
-        (
-            header::CONTENT_DISPOSITION,
-            format!("attachment; filename=\"{id}.zip\""),
-        ),
-    ];
-
-    Ok((headers, body))
-}
-
-/// A list of template IDs known by the service
-#[derive(ToSchema, Serialize)]
-struct TemplateList(Vec<&'static str>);
-
-#[utoipa::path(
-    method(get),
-    tag = super::TEMPLATE_TAG,
-    path = "",
-    description = "Get a list of all template IDs known to the service.",
-    responses(
-        (status = OK, description = "Success", body = TemplateList, content_type = "application/json")
-    )
-)]
-async fn get_template_list() -> impl IntoResponse {
-    Json(TemplateList(
-        TEMPLATES
-            .iter()
-            .map(|(id, _version)| id.to_owned())
-            .collect(),
-    ))
+/// Status and JSON body of the response that a request failing with the given error gets.
+///
+/// Used to report failures of work that is not answered with its own response.
+async fn error_body(error: TemplateError) -> (StatusCode, serde_json::Value) {
+    let response = error.into_response();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .ok()
+        .and_then(|body| serde_json::from_slice(&body).ok())
+        .unwrap_or_default();
+
+    (status, body)
 }
 
 #[derive(ToSchema, Deserialize)]
 #[schema(example = json!({
+    "mode": "production",
     "jsonInputs": [
         {
             "key": "data",
@@ -415,10 +795,38 @@ async fn get_template_list() -> impl IntoResponse {
     ]
 }))]
 struct CompilationPayload {
+    /// Development mode uses the development values of inputs that are not part of the
+    /// request. Default values of inputs are used in both modes. Defaults to the mode
+    /// configured for the service.
+    #[serde(default)]
+    mode: Option<Mode>,
     #[serde(rename = "jsonInputs")]
     json_inputs: Vec<JsonInput>,
     #[serde(default, rename = "blobInputs")]
     blob_inputs: Vec<BlobInput>,
+    /// PDF standards to conform to instead of those declared by the template, like `a-2b`
+    /// or `ua-1`. Typst supports one PDF/A or PDF/UA standard at a time, optionally combined
+    /// with a PDF version like `1.7`. Accessibility standards of a template cannot be
+    /// dropped. Only used for PDF output.
+    #[serde(default, rename = "pdfStandards")]
+    pdf_standards: Vec<String>,
+}
+
+/// Mode to compile a template in
+#[derive(ToSchema, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Mode {
+    Production,
+    Development,
+}
+
+impl From<Mode> for CompilationMode {
+    fn from(mode: Mode) -> Self {
+        match mode {
+            Mode::Production => CompilationMode::Production,
+            Mode::Development => CompilationMode::Development,
+        }
+    }
 }
 
 #[derive(ToSchema, Deserialize)]
@@ -0,0 +1,155 @@
+use axum::{
+    Json,
+    body::Body,
+    extract::{Path, State},
+    http::{StatusCode, header},
+    response::IntoResponse,
+};
+use serde::Deserialize;
+use tokio_util::io::ReaderStream;
+use tracing::{error, info};
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use super::{
+    AppState, CompilationPayload, TEMPLATE_ID_DESCRIPTION, TemplateError, TemplatePath,
+    VERSION_DESCRIPTION, error_body, render::render_version, with_latest,
+};
+use crate::{
+    batch::{BatchArchive, BatchFilenames, BatchManifest, batch_stream, entry_filename},
+    diagnostics::parse_rendered,
+    render::RenderOutput,
+};
+
+/// Routes that render a template for many inputs at once
+pub(super) fn router() -> OpenApiRouter<AppState> {
+    OpenApiRouter::new().routes(with_latest(routes!(batch_template)))
+}
+
+/// Entry of a batch: the inputs for one PDF and its name in the archive
+#[derive(ToSchema, Deserialize)]
+#[schema(example = json!({
+    "filename": "frank.pdf",
+    "jsonInputs": [
+        {
+            "key": "data",
+            "value": {
+                "description": "from sample data",
+                "rows": [
+                    {
+                        "name": "Frank",
+                        "one": "first",
+                        "two": "second",
+                        "three": "third"
+                    }
+                ]
+            }
+        }
+    ]
+}))]
+struct BatchEntry {
+    /// Name of the PDF in the archive. `.pdf` is added if it is missing. Names must be
+    /// unique within a batch, including the names of entries that fail.
+    filename: String,
+    #[serde(flatten)]
+    payload: CompilationPayload,
+}
+
+/// Size of the buffer between the batch and the response body.
+const BATCH_BUFFER_SIZE: usize = 1 << 20;
+
+#[utoipa::path(
+    method(post),
+    tag = crate::TEMPLATE_TAG,
+    path = "/{template_id}/versions/{version}/batch",
+    params(
+        ("template_id" = String, example = "table", description = TEMPLATE_ID_DESCRIPTION),
+        ("version" = String, example = "0.1.0", description = VERSION_DESCRIPTION)
+    ),
+    request_body(content = Vec<BatchEntry>, description = "Inputs and filename of every PDF to render", content_type = "application/json"),
+    description = "Compile a template once for every entry and return the PDFs as zip archive. The archive is streamed while the entries compile, so it is not limited by the request timeouts. Entries that fail are left out of the archive and do not stop the batch. If the archive itself cannot be written, the response is aborted.",
+    responses(
+        (status = OK, description = "Zip archive with one PDF per successful entry. The `manifest.json` at the end of the archive reports the outcome of the batch: `template_id`, `version`, the number of `succeeded` and `failed` entries, and the `entries` in the order of the request. Each entry has its `index`, its `filename` in the archive and whether it was a `success`. Successful entries list their `warnings`, failed entries the `status_code` and `error` response that a request for only this entry would have gotten.", content_type = "application/zip")
+    )
+)]
+#[axum::debug_handler]
+async fn batch_template(
+    State(state): State<AppState>,
+    Path(TemplatePath { id, version }): Path<TemplatePath>,
+    Json(entries): Json<Vec<BatchEntry>>,
+) -> Result<impl IntoResponse, TemplateError> {
+    let key = state.resolve(id, &version)?;
+    state.template_pool(&key).await?;
+    let disposition = format!("attachment; filename=\"{}-batch.zip\"", key.id);
+    let (mut writer, reader) = batch_stream(BATCH_BUFFER_SIZE);
+
+    // Entries compile one after another on the instances of the cached template. The batch
+    // stops if the client goes away.
+    tokio::spawn(async move {
+        let count = entries.len();
+        let mut archive = BatchArchive::new();
+        let mut manifest = BatchManifest::new(key.id.clone(), key.version.to_string());
+        let mut filenames = BatchFilenames::default();
+
+        for entry in entries {
+            let filename = entry_filename(&entry.filename);
+            if let Err(error) = filenames.reserve(&filename) {
+                manifest.failed(
+                    filename,
+                    StatusCode::BAD_REQUEST.as_u16(),
+                    serde_json::json!({ "message": error }),
+                );
+                continue;
+            }
+
+            let output = Ok(RenderOutput::Pdf { pages: None });
+            let chunk = match render_version(&state, &key, output, entry.payload).await {
+                Ok((rendered, warnings)) => {
+                    let chunk = archive.add(&filename, &rendered.body);
+                    manifest.succeeded(
+                        filename,
+                        warnings.as_deref().map(parse_rendered).unwrap_or_default(),
+                    );
+                    chunk
+                }
+                Err(error) => {
+                    let (status, body) = error_body(error).await;
+                    manifest.failed(filename, status.as_u16(), body);
+                    continue;
+                }
+            };
+            let sent = match chunk {
+                Ok(chunk) => writer.write(&chunk).await,
+                Err(error) => {
+                    error!(template_id = %key.id, %error, "Failed to write the batch archive of {key}: {error}");
+                    writer.fail();
+                    return;
+                }
+            };
+            if sent.is_err() {
+                info!(template_id = %key.id, "Stopped the batch of {key}, the client went away");
+                return;
+            }
+        }
+
+        match archive.finish(&manifest) {
+            Ok(chunk) => {
+                if writer.write(&chunk).await.is_ok() {
+                    info!(template_id = %key.id, "Rendered a batch of {key} with {count} entries, {} failed", manifest.failed_count());
+                }
+            }
+            Err(error) => {
+                error!(template_id = %key.id, %error, "Failed to write the batch archive of {key}: {error}");
+                writer.fail();
+            }
+        }
+    });
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_owned()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        Body::from_stream(ReaderStream::new(reader)),
+    ))
+}
@@ -0,0 +1,539 @@
+use axum::{
+    Json,
+    body::Body,
+    extract::{Path, State},
+    http::{StatusCode, header},
+    response::IntoResponse,
+};
+use oicana_input::input_definition::InputDefinition;
+use oicana_template::manifest::TemplateManifest;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use tokio_util::io::ReaderStream;
+use tracing::{error, info};
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+use uuid::Uuid;
+
+use super::{
+    AppState, TEMPLATE_ID_DESCRIPTION, TemplateError, TemplatePath, VERSION_DESCRIPTION,
+    with_latest,
+};
+use crate::{
+    cache::TemplateStatus,
+    diagnostics::{Diagnostic, parse_rendered},
+    pool::TemplatePool,
+    schema::read_file,
+};
+
+/// Routes that describe templates and manage their cache
+pub(super) fn router() -> OpenApiRouter<AppState> {
+    OpenApiRouter::new()
+        .routes(routes!(reset_template))
+        .routes(routes!(reset_template_version))
+        .routes(with_latest(routes!(get_template)))
+        .routes(routes!(get_template_versions))
+        .routes(with_latest(routes!(get_template_manifest)))
+        .routes(with_latest(routes!(get_input_schema)))
+        .routes(with_latest(routes!(get_input_example)))
+        .routes(routes!(get_template_pool))
+        .routes(routes!(get_template_warnings))
+        .routes(routes!(get_template_list))
+}
+
+#[utoipa::path(
+    method(post),
+    tag = crate::TEMPLATE_TAG,
+    path = "/{template_id}/reset",
+    params(("template_id" = String, example = "table", description = TEMPLATE_ID_DESCRIPTION)),
+    description = "Reset (remove) all versions of a template from the cache. The template will be reloaded on next use.",
+    responses(
+        (status = NO_CONTENT, description = "Template successfully removed from cache"),
+        (status = NOT_FOUND, description = "Template not found in cache")
+    )
+)]
+async fn reset_template(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    if state.template_cache.remove(&id, None) {
+        info!("Template '{}' removed from cache", id);
+        StatusCode::NO_CONTENT
+    } else {
+        error!("Template '{}' not found in cache", id);
+        StatusCode::NOT_FOUND
+    }
+}
+
+#[utoipa::path(
+    method(post),
+    tag = crate::TEMPLATE_TAG,
+    path = "/{template_id}/versions/{version}/reset",
+    params(
+        ("template_id" = String, example = "table", description = TEMPLATE_ID_DESCRIPTION),
+        ("version" = String, example = "0.1.0", description = VERSION_DESCRIPTION)
+    ),
+    description = "Reset (remove) a specific version of a template from the cache. The template will be reloaded on next use.",
+    responses(
+        (status = NO_CONTENT, description = "Template successfully removed from cache"),
+        (status = NOT_FOUND, description = "Template not found in cache")
+    )
+)]
+async fn reset_template_version(
+    State(state): State<AppState>,
+    Path(TemplatePath { id, version }): Path<TemplatePath>,
+) -> Result<StatusCode, TemplateError> {
+    let key = state.resolve(id, &version)?;
+    if state.template_cache.remove(&key.id, Some(&key.version)) {
+        info!("Template {key} removed from cache");
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        error!("Template {key} not found in cache");
+        Ok(StatusCode::NOT_FOUND)
+    }
+}
+
+#[utoipa::path(
+    method(get),
+    tag = crate::TEMPLATE_TAG,
+    path = "/{template_id}/versions/{version}",
+    params(
+        ("template_id" = String, example = "table", description = TEMPLATE_ID_DESCRIPTION),
+        ("version" = String, example = "0.1.0", description = VERSION_DESCRIPTION)
+    ),
+    description = "Download a packed template.",
+    responses(
+        (status = OK, description = "Success", content_type = "application/zip")
+    )
+)]
+async fn get_template(
+    State(state): State<AppState>,
+    Path(TemplatePath { id, version }): Path<TemplatePath>,
+) -> Result<impl IntoResponse, TemplateError> {
+    let key = state.resolve(id, &version)?;
+    let file = match tokio::fs::File::open(state.template_cache.path(&key)).await {
+        Ok(file) => file,
+        Err(error) => {
+            error!("Failed to open packed template {key}: {error}");
+            return Err(TemplateError::VersionNotFound {
+                id: key.id,
+                version: key.version,
+            });
+        }
+    };
+
+    let stream = ReaderStream::new(file);
+    let body = Body::from_stream(stream);
+
+    let headers = [
+        (header::CONTENT_TYPE, "application/zip".to_owned()),
+        (
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}-{}.zip\"", key.id, key.version),
+        ),
+    ];
+
+    Ok((headers, body))
+}
+
+#[utoipa::path(
+    method(get),
+    tag = crate::TEMPLATE_TAG,
+    path = "/{template_id}/versions/{version}/manifest",
+    params(
+        ("template_id" = String, example = "invoice", description = TEMPLATE_ID_DESCRIPTION),
+        ("version" = String, example = "0.1.0", description = VERSION_DESCRIPTION)
+    ),
+    description = "Get the manifest of a template, including its inputs and export settings.",
+    responses(
+        (status = OK, description = "The parsed `typst.toml` manifest of the template", body = serde_json::Value, content_type = "application/json", example = json!(manifest_example())),
+        (status = NOT_FOUND, description = "Template not found")
+    )
+)]
+async fn get_template_manifest(
+    State(state): State<AppState>,
+    Path(TemplatePath { id, version }): Path<TemplatePath>,
+) -> Result<Json<TemplateManifest>, TemplateError> {
+    let key = state.resolve(id, &version)?;
+    let pool = state.template_pool(&key).await?;
+
+    Ok(Json(pool.manifest().clone()))
+}
+
+/// Example of a manifest for the API documentation
+fn manifest_example() -> serde_json::Value {
+    serde_json::json!({
+        "package": {
+            "name": "invoice",
+            "version": "0.1.0",
+            "entrypoint": "main.typ",
+            "authors": ["Jane Doe <jane@example.com>"],
+            "description": "Invoice template with customizable items and billing details."
+        },
+        "template": null,
+        "tool": {
+            "oicana": {
+                "manifest_version": 1,
+                "inputs": [
+                    {
+                        "type": "json",
+                        "key": "invoice",
+                        "default": "invoice.json",
+                        "development": null,
+                        "schema": "invoice.schema.json"
+                    },
+                    {
+                        "type": "blob",
+                        "key": "banner",
+                        "default": {"file": "oicana.png", "meta": {"image_format": "png"}},
+                        "development": null
+                    }
+                ],
+                "tests": "tests",
+                "export": {"pdf": {"standards": ["a-3b"]}}
+            }
+        }
+    })
+}
+
+/// Input selected by the path of a request
+#[derive(Deserialize)]
+struct InputPath {
+    key: String,
+}
+
+#[utoipa::path(
+    method(get),
+    tag = crate::TEMPLATE_TAG,
+    path = "/{template_id}/versions/{version}/inputs/{key}/schema",
+    params(
+        ("template_id" = String, example = "invoice", description = TEMPLATE_ID_DESCRIPTION),
+        ("version" = String, example = "0.1.0", description = VERSION_DESCRIPTION),
+        ("key" = String, example = "invoice", description = "The key of a JSON input of the template.")
+    ),
+    description = "Get the JSON schema of an input of a template. JSON inputs are validated against it before compiling.",
+    responses(
+        (status = OK, description = "The JSON schema referenced in the manifest", body = serde_json::Value, content_type = "application/schema+json"),
+        (status = NOT_FOUND, description = "Template or input not found, or the input has no schema")
+    )
+)]
+async fn get_input_schema(
+    State(state): State<AppState>,
+    Path(TemplatePath { id, version }): Path<TemplatePath>,
+    Path(InputPath { key }): Path<InputPath>,
+) -> Result<impl IntoResponse, TemplateError> {
+    let template_key = state.resolve(id.clone(), &version)?;
+    let pool = state.template_pool(&template_key).await?;
+    input_definition(&id, &pool, &key)?;
+
+    match pool.schemas().get(&key) {
+        Some(schema) => Ok((
+            [(header::CONTENT_TYPE, "application/schema+json")],
+            Json(schema.clone()),
+        )),
+        None => Err(TemplateError::NoInputSchema { id, key }),
+    }
+}
+
+#[utoipa::path(
+    method(get),
+    tag = crate::TEMPLATE_TAG,
+    path = "/{template_id}/versions/{version}/inputs/{key}/example",
+    params(
+        ("template_id" = String, example = "invoice", description = TEMPLATE_ID_DESCRIPTION),
+        ("version" = String, example = "0.1.0", description = VERSION_DESCRIPTION),
+        ("key" = String, example = "invoice", description = "The key of an input of the template.")
+    ),
+    description = "Get an example value for an input of a template. This is the default value of the input, or its development value if it has no default.",
+    responses(
+        (status = OK, description = "The value of a JSON input", body = serde_json::Value, content_type = "application/json"),
+        (status = OK, description = "The file of a blob input", body = Vec<u8>, content_type = "application/octet-stream"),
+        (status = NOT_FOUND, description = "Template or input not found, or the input has no default or development value"),
+        (status = SERVICE_UNAVAILABLE, description = "Too many requests are waiting for the template")
+    )
+)]
+async fn get_input_example(
+    State(state): State<AppState>,
+    Path(TemplatePath { id, version }): Path<TemplatePath>,
+    Path(InputPath { key }): Path<InputPath>,
+) -> Result<impl IntoResponse, TemplateError> {
+    let template_key = state.resolve(id.clone(), &version)?;
+    let pool = state.template_pool(&template_key).await?;
+    let (file, content_type) = match input_definition(&id, &pool, &key)? {
+        InputDefinition::Json(input) => (
+            input.default.clone().or_else(|| input.development.clone()),
+            "application/json".to_owned(),
+        ),
+        InputDefinition::Blob(input) => {
+            let fallback = input.default.as_ref().or(input.development.as_ref());
+            (
+                fallback.map(|fallback| fallback.file.clone()),
+                blob_content_type(fallback.and_then(|fallback| fallback.meta.as_ref())),
+            )
+        }
+    };
+    let Some(file) = file else {
+        return Err(TemplateError::NoInputExample { id, key });
+    };
+
+    let template = state.checkout(&id, &pool).await?;
+    let bytes =
+        read_file(&template, &file).map_err(|error| TemplateError::UnreadableInputFile {
+            id: id.clone(),
+            key,
+            error,
+        })?;
+    drop(template);
+
+    let file_name = file.rsplit('/').next().unwrap_or(&file);
+    let headers = [
+        (header::CONTENT_TYPE, content_type),
+        (
+            header::CONTENT_DISPOSITION,
+            format!("inline; filename=\"{file_name}\""),
+        ),
+    ];
+
+    Ok((headers, bytes))
+}
+
+/// Find an input in the manifest of a template.
+fn input_definition<'a>(
+    id: &str,
+    pool: &'a TemplatePool,
+    key: &str,
+) -> Result<&'a InputDefinition, TemplateError> {
+    pool.manifest()
+        .tool
+        .oicana
+        .inputs
+        .iter()
+        .find(|definition| match definition {
+            InputDefinition::Json(input) => input.key == key,
+            InputDefinition::Blob(input) => input.key == key,
+        })
+        .ok_or_else(|| TemplateError::InputNotFound {
+            id: id.to_owned(),
+            key: key.to_owned(),
+        })
+}
+
+/// Content type of a blob input file based on the `image_format` in its metadata.
+fn blob_content_type(meta: Option<&toml::Value>) -> String {
+    let format = meta
+        .and_then(|meta| meta.get("image_format"))
+        .and_then(toml::Value::as_str);
+
+    match format {
+        Some("svg") => "image/svg+xml".to_owned(),
+        Some("jpg") => "image/jpeg".to_owned(),
+        Some(format) => format!("image/{format}"),
+        None => "application/octet-stream".to_owned(),
+    }
+}
+
+/// Available versions of a template in ascending order
+#[derive(ToSchema, Serialize)]
+struct TemplateVersionList(Vec<String>);
+
+#[utoipa::path(
+    method(get),
+    tag = crate::TEMPLATE_TAG,
+    path = "/{template_id}/versions",
+    params(("template_id" = String, example = "invoice", description = TEMPLATE_ID_DESCRIPTION)),
+    description = "Get all versions of a template that are available to the service.",
+    responses(
+        (status = OK, description = "Success", body = TemplateVersionList, content_type = "application/json"),
+        (status = NOT_FOUND, description = "Template not found")
+    )
+)]
+async fn get_template_versions(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<TemplateVersionList>, TemplateError> {
+    match state.template_cache.versions(&id) {
+        Some(versions) => Ok(Json(TemplateVersionList(
+            versions.iter().map(Version::to_string).collect(),
+        ))),
+        None => Err(TemplateError::NotFound(id)),
+    }
+}
+
+/// Usage of the instance pools of all cached versions of a template
+#[derive(ToSchema, Serialize)]
+struct TemplatePoolList(Vec<TemplatePoolStats>);
+
+/// Usage of the instance pool of one template version
+#[derive(ToSchema, Serialize)]
+struct TemplatePoolStats {
+    #[schema(example = "0.1.0")]
+    version: String,
+    /// Number of prepared instances
+    #[schema(example = 4)]
+    size: usize,
+    /// Instances that are currently not in use
+    #[schema(example = 3)]
+    available: usize,
+    /// Requests currently waiting for an instance
+    #[schema(example = 0)]
+    waiting: usize,
+    /// Requests that got an instance since the template was loaded
+    #[schema(example = 120)]
+    checkouts: u64,
+    /// Requests rejected because too many requests were waiting
+    #[schema(example = 0)]
+    rejected: u64,
+    /// Average time requests waited for an instance in milliseconds
+    #[schema(example = 1.5)]
+    average_wait_ms: f64,
+    /// Longest time a request waited for an instance in milliseconds
+    #[schema(example = 12.0)]
+    max_wait_ms: f64,
+}
+
+#[utoipa::path(
+    method(get),
+    tag = crate::TEMPLATE_TAG,
+    path = "/{template_id}/pool",
+    params(("template_id" = String, example = "certificate", description = TEMPLATE_ID_DESCRIPTION)),
+    description = "Get usage statistics of the instance pools of all cached versions of a template.",
+    responses(
+        (status = OK, description = "Success", body = TemplatePoolList, content_type = "application/json"),
+        (status = NOT_FOUND, description = "Template not found")
+    )
+)]
+async fn get_template_pool(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<TemplatePoolList>, TemplateError> {
+    if state.template_cache.versions(&id).is_none() {
+        return Err(TemplateError::NotFound(id));
+    }
+
+    let pools = state
+        .template_cache
+        .pool_stats(&id)
+        .into_iter()
+        .map(|(version, stats)| TemplatePoolStats {
+            version: version.to_string(),
+            size: stats.size,
+            available: stats.available,
+            waiting: stats.waiting,
+            checkouts: stats.checkouts,
+            rejected: stats.rejected,
+            average_wait_ms: if stats.checkouts == 0 {
+                0.0
+            } else {
+                stats.total_wait.as_secs_f64() * 1000.0 / stats.checkouts as f64
+            },
+            max_wait_ms: stats.max_wait.as_secs_f64() * 1000.0,
+        })
+        .collect();
+
+    Ok(Json(TemplatePoolList(pools)))
+}
+
+/// Warnings of a successful compilation
+#[derive(ToSchema, Serialize)]
+struct CompilationWarningsResponse {
+    diagnostics: Vec<Diagnostic>,
+}
+
+#[utoipa::path(
+    method(get),
+    tag = crate::TEMPLATE_TAG,
+    path = "/{template_id}/warnings/{warnings_id}",
+    params(
+        ("template_id" = String, example = "table", description = "The identifier of the compiled template."),
+        ("warnings_id" = Uuid, description = "The identifier from the `link` header of a compilation response.")
+    ),
+    description = "Get the warnings of a recent successful compilation. Responses of compilations with warnings link here.",
+    responses(
+        (status = OK, description = "Success", body = CompilationWarningsResponse, content_type = "application/json"),
+        (status = NOT_FOUND, description = "The warnings are unknown or were already dropped")
+    )
+)]
+async fn get_template_warnings(
+    State(state): State<AppState>,
+    Path((id, warnings_id)): Path<(String, Uuid)>,
+) -> Result<Json<CompilationWarningsResponse>, TemplateError> {
+    match state.warnings.get(&id, warnings_id) {
+        Some(rendered) => Ok(Json(CompilationWarningsResponse {
+            diagnostics: parse_rendered(&rendered),
+        })),
+        None => Err(TemplateError::WarningsNotFound { id, warnings_id }),
+    }
+}
+
+/// All templates found in the templates directory
+#[derive(ToSchema, Serialize)]
+struct TemplateList(Vec<TemplateListEntry>);
+
+/// A template and the status of its versions
+#[derive(ToSchema, Serialize)]
+struct TemplateListEntry {
+    /// The identifier of the template
+    #[schema(example = "invoice")]
+    id: String,
+    /// All versions of the template in ascending order
+    versions: Vec<TemplateVersionStatus>,
+}
+
+/// Status of one version of a template
+#[derive(ToSchema, Serialize)]
+struct TemplateVersionStatus {
+    #[schema(example = "0.1.0")]
+    version: String,
+    status: TemplateVersionState,
+    /// Why the template could not be loaded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Whether a template version could be loaded
+#[derive(ToSchema, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum TemplateVersionState {
+    /// The template is ready to compile
+    Ready,
+    /// The template failed to load, see `error` for the reason
+    Failed,
+}
+
+#[utoipa::path(
+    method(get),
+    tag = crate::TEMPLATE_TAG,
+    path = "",
+    description = "Get all templates known to the service, including versions that failed to load and why.",
+    responses(
+        (status = OK, description = "Success", body = TemplateList, content_type = "application/json")
+    )
+)]
+async fn get_template_list(State(state): State<AppState>) -> impl IntoResponse {
+    let templates = state
+        .template_cache
+        .list()
+        .into_iter()
+        .map(|(id, versions)| TemplateListEntry {
+            id,
+            versions: versions
+                .into_iter()
+                .map(|(version, status)| {
+                    let (status, error) = match status {
+                        TemplateStatus::Ready => (TemplateVersionState::Ready, None),
+                        TemplateStatus::Failed(error) => {
+                            (TemplateVersionState::Failed, Some(error))
+                        }
+                    };
+                    TemplateVersionStatus {
+                        version: version.to_string(),
+                        status,
+                        error,
+                    }
+                })
+                .collect(),
+        })
+        .collect();
+
+    Json(TemplateList(templates))
+}
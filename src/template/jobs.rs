@@ -0,0 +1,266 @@
+use axum::{
+    Json,
+    body::Body,
+    extract::{Path, Query, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+use uuid::Uuid;
+
+use super::{
+    AppState, CompilationPayload, LATEST, TemplateError, error_body,
+    render::{pdf, prepare_render},
+};
+use crate::{
+    diagnostics::parse_rendered,
+    job::{JobFailure, JobOutcome, JobOutput, JobStatus, QueueFull},
+    pdf::PdfQuery,
+};
+
+/// Routes that render templates in the background
+pub(super) fn router() -> OpenApiRouter<AppState> {
+    OpenApiRouter::new()
+        .routes(routes!(submit_job))
+        .routes(routes!(get_job))
+        .routes(routes!(get_job_result))
+}
+
+/// Request to render a template as PDF in the background
+#[derive(ToSchema, Deserialize)]
+#[schema(example = json!({
+    "templateId": "table",
+    "jsonInputs": [
+        {
+            "key": "data",
+            "value": {
+                "description": "from sample data",
+                "rows": [
+                    {
+                        "name": "Frank",
+                        "one": "first",
+                        "two": "second",
+                        "three": "third"
+                    }
+                ]
+            }
+        }
+    ]
+}))]
+struct JobRequest {
+    /// The identifier of the template to render
+    #[serde(rename = "templateId")]
+    template_id: String,
+    /// Version of the template, like `0.1.0`. Defaults to the latest version.
+    #[serde(default)]
+    version: Option<String>,
+    /// URL to post the status of the job to once it finished. The request is signed with
+    /// the HMAC-SHA256 of `{timestamp}.{body}`, see the `x-oicana-timestamp` and
+    /// `x-oicana-signature` headers. The URL needs to point to a public address, unless its
+    /// host is allowed in the configuration. Redirects are not followed.
+    #[serde(default, rename = "callbackUrl")]
+    callback_url: Option<String>,
+    #[serde(flatten)]
+    payload: CompilationPayload,
+}
+
+#[utoipa::path(
+    method(post),
+    tag = crate::JOB_TAG,
+    path = "",
+    params(PdfQuery),
+    request_body(content = JobRequest, description = "Template and inputs to render", content_type = "application/json"),
+    description = "Render a template as PDF in the background. Use this for documents that take longer than a request may. Poll the status of the job until it succeeded, then download the result. Jobs are not limited by the request timeouts.",
+    responses(
+        (status = ACCEPTED, description = "The job was queued. The `location` header points to its status.", body = JobStatus, content_type = "application/json"),
+        (status = BAD_REQUEST, description = "The version, the selected pages, the PDF standards or the callback URL are invalid"),
+        (status = NOT_FOUND, description = "The template or version does not exist"),
+        (status = SERVICE_UNAVAILABLE, description = "Too many jobs are queued")
+    )
+)]
+#[axum::debug_handler]
+async fn submit_job(
+    State(state): State<AppState>,
+    Query(query): Query<PdfQuery>,
+    Json(request): Json<JobRequest>,
+) -> Result<impl IntoResponse, TemplateError> {
+    let version = request.version.as_deref().unwrap_or(LATEST);
+    let key = state.resolve(request.template_id, version)?;
+    let callback = match request.callback_url {
+        Some(url) => Some(state.jobs.check_callback(&url).await.map_err(|error| {
+            TemplateError::InvalidCallback {
+                id: key.id.clone(),
+                error,
+            }
+        })?),
+        None => None,
+    };
+    // Invalid options fail the request instead of the job
+    let prepared = prepare_render(&state, &key, pdf(query), &request.payload.pdf_standards).await?;
+    let render = {
+        let state = state.clone();
+        async move {
+            match prepared.run(&state, request.payload).await {
+                Ok((rendered, warnings)) => Ok(JobOutput {
+                    content_type: rendered.content_type,
+                    disposition: rendered.disposition.unwrap_or_default(),
+                    body: rendered.body.into(),
+                    warnings: warnings.as_deref().map(parse_rendered).unwrap_or_default(),
+                }),
+                Err(error) => {
+                    let (status, body) = error_body(error).await;
+                    Err(JobFailure { status, body })
+                }
+            }
+        }
+    };
+
+    let status = state
+        .jobs
+        .submit(key.id.clone(), key.version.to_string(), callback, render)
+        .map_err(|QueueFull| TemplateError::JobQueueFull(key.id))?;
+    let location = format!("/jobs/{}", status.id());
+
+    Ok((
+        StatusCode::ACCEPTED,
+        [(header::LOCATION, location)],
+        Json(status),
+    ))
+}
+
+#[utoipa::path(
+    method(get),
+    tag = crate::JOB_TAG,
+    path = "/{job_id}",
+    params(
+        ("job_id" = Uuid, description = "The identifier of the job.")
+    ),
+    description = "Get the status of a render job. Failed jobs include the error response that a synchronous request would have gotten, with the diagnostics of compilation failures.",
+    responses(
+        (status = OK, description = "Success", body = JobStatus, content_type = "application/json"),
+        (status = NOT_FOUND, description = "The job is unknown or its retention period ended")
+    )
+)]
+async fn get_job(
+    State(state): State<AppState>,
+    Path(job_id): Path<Uuid>,
+) -> Result<Json<JobStatus>, TemplateError> {
+    state
+        .jobs
+        .status(job_id)
+        .map(Json)
+        .ok_or_else(|| TemplateError::JobNotFound {
+            job_id,
+            retention: state.jobs.retention(),
+        })
+}
+
+#[utoipa::path(
+    method(get),
+    tag = crate::JOB_TAG,
+    path = "/{job_id}/result",
+    params(
+        ("job_id" = Uuid, description = "The identifier of the job.")
+    ),
+    description = "Download the PDF of a succeeded render job. Failed jobs respond with the error that a synchronous request would have gotten.",
+    responses(
+        (status = OK, description = "The rendered PDF", content_type = "application/pdf"),
+        (status = NOT_FOUND, description = "The job is unknown or its retention period ended"),
+        (status = CONFLICT, description = "The job did not finish yet")
+    )
+)]
+async fn get_job_result(
+    State(state): State<AppState>,
+    Path(job_id): Path<Uuid>,
+) -> Result<Response, TemplateError> {
+    match state.jobs.outcome(job_id) {
+        Some(JobOutcome::Succeeded(output)) => Ok((
+            [
+                (header::CONTENT_TYPE, output.content_type),
+                (header::CONTENT_DISPOSITION, output.disposition),
+            ],
+            Body::from(output.body),
+        )
+            .into_response()),
+        Some(JobOutcome::Failed(failure)) => {
+            Ok((failure.status, Json(failure.body)).into_response())
+        }
+        Some(JobOutcome::Unfinished(job_state)) => {
+            Err(TemplateError::JobNotFinished { job_id, job_state })
+        }
+        None => Err(TemplateError::JobNotFound {
+            job_id,
+            retention: state.jobs.retention(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use axum::{body::to_bytes, http::Request};
+    use oicana_input::CompilationMode;
+    use serde_json::{Value, json};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        blob,
+        cache::TemplateCache,
+        config::{JobConfig, PoolConfig},
+        job::JobStore,
+        warnings::WarningStore,
+        worker::CompilePool,
+    };
+
+    #[tokio::test]
+    async fn rejects_invalid_options_without_creating_a_job() {
+        let jobs = JobStore::new(&JobConfig::default());
+        let app: axum::Router = super::super::router(
+            blob::router().1,
+            Arc::new(TemplateCache::warmed_up(
+                PathBuf::from("templates"),
+                PoolConfig::default(),
+            )),
+            Arc::new(CompilePool::new(1)),
+            Arc::new(WarningStore::new(10)),
+            jobs.clone(),
+            CompilationMode::Production,
+        )
+        .into();
+
+        for (uri, request, error) in [
+            (
+                "/jobs?pages=first",
+                json!({ "templateId": "table", "jsonInputs": [] }),
+                "first",
+            ),
+            (
+                "/jobs",
+                json!({ "templateId": "table", "jsonInputs": [], "pdfStandards": ["a-9z"] }),
+                "a-9z",
+            ),
+        ] {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::post(uri)
+                        .header(header::CONTENT_TYPE, "application/json")
+                        .body(Body::from(request.to_string()))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            let body: Value =
+                serde_json::from_slice(&to_bytes(response.into_body(), usize::MAX).await.unwrap())
+                    .unwrap();
+            assert!(body["message"].as_str().unwrap().contains(error), "{body}");
+        }
+        assert!(jobs.is_empty());
+    }
+}
@@ -0,0 +1,204 @@
+use axum::{Json, body::Body, extract::State, http::header, response::IntoResponse};
+use serde::Deserialize;
+use tracing::info;
+use typst::layout::PagedDocument;
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use super::{AppState, CompilationPayload, InvalidInputsResponse, LATEST, TemplateError};
+use crate::{
+    cache::{CachedTemplate, TemplateKey},
+    diagnostics::CompilationFailureResponse,
+    merge::{DocumentPart, merge_documents},
+    pdf::{
+        export_standards, export_untagged_pdf, requires_tags, standard_name, strictest_standards,
+    },
+    warnings::CompilationWarnings,
+    worker::JobPanicked,
+};
+
+/// Routes that merge several templates into one PDF
+pub(super) fn router() -> OpenApiRouter<AppState> {
+    OpenApiRouter::new().routes(routes!(merge_templates))
+}
+
+/// Part of a merged PDF: a template with its inputs
+///
+/// Merged PDFs are not tagged, so parts cannot conform to standards that require tags, like
+/// `ua-1` or `a-2a`.
+#[derive(ToSchema, Deserialize)]
+#[schema(example = json!({
+    "templateId": "table",
+    "title": "Sample data",
+    "jsonInputs": [
+        {
+            "key": "data",
+            "value": {
+                "description": "from sample data",
+                "rows": [
+                    {
+                        "name": "Frank",
+                        "one": "first",
+                        "two": "second",
+                        "three": "third"
+                    }
+                ]
+            }
+        }
+    ]
+}))]
+struct MergePart {
+    /// The identifier of the template to render
+    #[serde(rename = "templateId")]
+    template_id: String,
+    /// Version of the template, like `0.1.0`. Defaults to the latest version.
+    #[serde(default)]
+    version: Option<String>,
+    /// Title of the bookmark pointing to the part. Defaults to the title of the rendered
+    /// document, or the identifier of the template.
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(flatten)]
+    payload: CompilationPayload,
+}
+
+#[utoipa::path(
+    method(post),
+    tag = crate::TEMPLATE_TAG,
+    path = "",
+    request_body(content = Vec<MergePart>, description = "Templates and inputs of the parts, in the order of the merged PDF", content_type = "application/json"),
+    description = "Render several templates and merge them into one PDF, like a cover letter, an invoice and the terms. Every part is compiled from the cached templates and starts on a new page with a bookmark of its own. The PDF conforms to the strictest PDF standards of the parts, either declared by their templates or requested with `pdfStandards`.\n\n**Limitation:** merged PDFs are not tagged, because the tags of separately compiled parts cannot be combined. Parts that require a tagged PDF are rejected: templates declaring `ua-1`, like the accessibility template, and parts conforming to a PDF/A standard of level `a`, like `a-2a`. Render these templates on their own instead.",
+    responses(
+        (status = OK, description = "The merged PDF. If the compilation of parts produced warnings, the `x-compilation-warnings` header holds their number and the `link` header points to them.", content_type = "application/pdf"),
+        (status = BAD_REQUEST, description = "A part failed to compile with its inputs, a part requires a tagged PDF, or the PDF standards of the parts cannot be combined. Request `text/plain` for the rendered diagnostics.", content(
+            (CompilationFailureResponse = "application/json"),
+            (String = "text/plain")
+        )),
+        (status = NOT_FOUND, description = "The template or version of a part does not exist"),
+        (status = UNPROCESSABLE_ENTITY, description = "JSON inputs of a part do not match the schemas declared in the template's manifest. Request `text/plain` for a list of the mismatches.", content(
+            (InvalidInputsResponse = "application/json"),
+            (String = "text/plain")
+        ))
+    )
+)]
+#[axum::debug_handler]
+async fn merge_templates(
+    State(state): State<AppState>,
+    Json(parts): Json<Vec<MergePart>>,
+) -> Result<impl IntoResponse, TemplateError> {
+    if parts.is_empty() {
+        return Err(TemplateError::InvalidMerge(
+            "A merged PDF needs at least one part.".to_owned(),
+        ));
+    }
+
+    let count = parts.len();
+    let mut documents = Vec::with_capacity(count);
+    let mut standards = Vec::new();
+    let mut declared = Vec::with_capacity(count);
+    let mut warnings = Vec::with_capacity(count);
+    for (index, part) in parts.into_iter().enumerate() {
+        let version = part.version.as_deref().unwrap_or(LATEST);
+        let key = state.resolve(part.template_id, version)?;
+        let pool = state.template_pool(&key).await?;
+        let declared_standards = pool.manifest().tool.oicana.export.pdf.standards.clone();
+        let part_standards = export_standards(&declared_standards, &part.payload.pdf_standards)
+            .map_err(|error| TemplateError::InvalidPdfStandards {
+                id: key.id.clone(),
+                error,
+            })?;
+        if requires_tags(&part_standards) {
+            return Err(TemplateError::InvalidMerge(format!(
+                "Part {} renders template '{}' as tagged PDF to conform to {}. Merged PDFs cannot be tagged, so parts cannot conform to 'ua-1' or PDF/A standards of level 'a'. Render the template on its own instead.",
+                index + 1,
+                key.id,
+                part_standards
+                    .iter()
+                    .map(|standard| format!("'{}'", standard_name(*standard)))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )));
+        }
+
+        let (document, part_warnings) = compile_version(&state, &key, &pool, part.payload).await?;
+        let title = part
+            .title
+            .or_else(|| document.info.title.as_ref().map(ToString::to_string))
+            .unwrap_or_else(|| key.id.clone());
+        documents.push(DocumentPart { title, document });
+        standards.extend(part_standards);
+        declared.push(declared_standards);
+        warnings.push((key.id, part_warnings));
+    }
+
+    let standards = strictest_standards(&standards).map_err(TemplateError::InvalidMerge)?;
+    // Export failures with other standards than declared are likely caused by those standards
+    let overridden = declared.iter().any(|declared| *declared != standards);
+    let body = state
+        .compile_pool
+        .run(move |_| {
+            let document = merge_documents(documents);
+            export_untagged_pdf(&document, &standards).map_err(|error| {
+                if overridden {
+                    TemplateError::InvalidMerge(format!(
+                        "The parts do not conform to the PDF standards of the merged PDF.\n{error}"
+                    ))
+                } else {
+                    TemplateError::MergeFailure(error)
+                }
+            })
+        })
+        .await
+        .unwrap_or_else(|JobPanicked| {
+            Err(TemplateError::MergeFailure(
+                "The export was aborted.".to_owned(),
+            ))
+        })?;
+    info!("Merged {count} parts into one PDF");
+
+    let warnings: CompilationWarnings = warnings
+        .into_iter()
+        .map(|(id, warnings)| state.warnings.record(&id, warnings))
+        .collect();
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/pdf".to_owned()),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"merged.pdf\"".to_owned(),
+            ),
+        ],
+        warnings,
+        Body::from(body),
+    ))
+}
+
+/// Compile a template version with the inputs of a request, without exporting it.
+async fn compile_version(
+    state: &AppState,
+    key: &TemplateKey,
+    pool: &CachedTemplate,
+    payload: CompilationPayload,
+) -> Result<(PagedDocument, Option<String>), TemplateError> {
+    let id = key.id.clone();
+    let inputs = state.template_inputs(&id, pool, payload)?;
+    let mut template = state.checkout(&id, pool).await?;
+
+    state
+        .run(&id, {
+            let id = id.clone();
+            move |cancellation| {
+                let compilation_result = match template.compile(inputs) {
+                    Ok(document) => document,
+                    Err(error) => return Err(TemplateError::CompilationFailure { id, error }),
+                };
+                if cancellation.is_cancelled() {
+                    return Err(TemplateError::Cancelled(id));
+                }
+
+                Ok((compilation_result.document, compilation_result.warnings))
+            }
+        })
+        .await
+}
@@ -0,0 +1,438 @@
+use axum::{
+    Json,
+    body::Body,
+    extract::{Path, Query, State},
+    http::{HeaderMap, header},
+    response::{AppendHeaders, IntoResponse},
+};
+use oicana_template::PdfStandard;
+use serde::Serialize;
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use super::{
+    AppState, CompilationPayload, IMAGE_FAILURE_DESCRIPTION, INVALID_INPUTS_DESCRIPTION,
+    InvalidInputsResponse, PAYLOAD_DESCRIPTION, PDF_FAILURE_DESCRIPTION, TEMPLATE_ID_DESCRIPTION,
+    TemplateError, TemplatePath, VERSION_DESCRIPTION, WARNINGS_DESCRIPTION, with_latest,
+};
+use crate::{
+    cache::{CachedTemplate, TemplateKey},
+    diagnostics::{CompilationFailureResponse, Diagnostic, parse_rendered},
+    image::{ImageOptions, PngQuery, RenderError, SvgQuery, accepts_multipart, render},
+    pages::parse_pages,
+    pdf::{PdfQuery, export_pdf, export_standards, requires_tags},
+    render::{RenderOutput, RenderQuery, RenderRequestError},
+};
+
+/// Routes that compile a template and render it
+pub(super) fn router() -> OpenApiRouter<AppState> {
+    OpenApiRouter::new()
+        .routes(with_latest(routes!(compile_template)))
+        .routes(with_latest(routes!(preview_template)))
+        .routes(with_latest(routes!(svg_template)))
+        .routes(with_latest(routes!(render_template)))
+}
+
+#[utoipa::path(
+    method(post),
+    tag = crate::TEMPLATE_TAG,
+    path = "/{template_id}/versions/{version}/compile",
+    params(
+        ("template_id" = String, example = "table", description = TEMPLATE_ID_DESCRIPTION),
+        ("version" = String, example = "0.1.0", description = VERSION_DESCRIPTION),
+        PdfQuery
+    ),
+    request_body(content = CompilationPayload, description = PAYLOAD_DESCRIPTION, content_type = "application/json"),
+    description = "Compile a template with given inputs.",
+    responses(
+        (status = OK, description = WARNINGS_DESCRIPTION, content_type = "application/pdf"),
+        (status = BAD_REQUEST, description = PDF_FAILURE_DESCRIPTION, content(
+            (CompilationFailureResponse = "application/json"),
+            (String = "text/plain")
+        )),
+        (status = UNPROCESSABLE_ENTITY, description = INVALID_INPUTS_DESCRIPTION, content(
+            (InvalidInputsResponse = "application/json"),
+            (String = "text/plain")
+        ))
+    )
+)]
+#[axum::debug_handler]
+async fn compile_template(
+    State(state): State<AppState>,
+    Path(TemplatePath { id, version }): Path<TemplatePath>,
+    Query(query): Query<PdfQuery>,
+    Json(payload): Json<CompilationPayload>,
+) -> impl IntoResponse {
+    compile_and_render(state, id, version, pdf(query), payload).await
+}
+
+#[utoipa::path(
+    method(post),
+    tag = crate::TEMPLATE_TAG,
+    path = "/{template_id}/versions/{version}/preview",
+    params(
+        ("template_id" = String, example = "table", description = TEMPLATE_ID_DESCRIPTION),
+        ("version" = String, example = "0.1.0", description = VERSION_DESCRIPTION),
+        PngQuery
+    ),
+    request_body(content = CompilationPayload, description = PAYLOAD_DESCRIPTION, content_type = "application/json"),
+    description = "Generate a PNG preview of a template with given inputs.",
+    responses(
+        (status = OK, description = WARNINGS_DESCRIPTION, content(
+            ("image/png"),
+            ("application/zip"),
+            ("multipart/mixed")
+        )),
+        (status = BAD_REQUEST, description = IMAGE_FAILURE_DESCRIPTION, content(
+            (CompilationFailureResponse = "application/json"),
+            (String = "text/plain")
+        )),
+        (status = UNPROCESSABLE_ENTITY, description = INVALID_INPUTS_DESCRIPTION, content(
+            (InvalidInputsResponse = "application/json"),
+            (String = "text/plain")
+        ))
+    )
+)]
+#[axum::debug_handler]
+async fn preview_template(
+    State(state): State<AppState>,
+    Path(TemplatePath { id, version }): Path<TemplatePath>,
+    Query(query): Query<PngQuery>,
+    headers: HeaderMap,
+    Json(payload): Json<CompilationPayload>,
+) -> impl IntoResponse {
+    compile_and_render(
+        state,
+        id,
+        version,
+        images(query.options(), &headers),
+        payload,
+    )
+    .await
+}
+
+#[utoipa::path(
+    method(post),
+    tag = crate::TEMPLATE_TAG,
+    path = "/{template_id}/versions/{version}/svg",
+    params(
+        ("template_id" = String, example = "invoice", description = TEMPLATE_ID_DESCRIPTION),
+        ("version" = String, example = "0.1.0", description = VERSION_DESCRIPTION),
+        SvgQuery
+    ),
+    request_body(content = CompilationPayload, description = PAYLOAD_DESCRIPTION, content_type = "application/json"),
+    description = "Render the pages of a template with given inputs as SVG. Unlike PNG previews, SVGs stay sharp at any zoom level and keep text selectable.",
+    responses(
+        (status = OK, description = WARNINGS_DESCRIPTION, content(
+            ("image/svg+xml"),
+            ("application/zip"),
+            ("multipart/mixed")
+        )),
+        (status = BAD_REQUEST, description = IMAGE_FAILURE_DESCRIPTION, content(
+            (CompilationFailureResponse = "application/json"),
+            (String = "text/plain")
+        )),
+        (status = UNPROCESSABLE_ENTITY, description = INVALID_INPUTS_DESCRIPTION, content(
+            (InvalidInputsResponse = "application/json"),
+            (String = "text/plain")
+        ))
+    )
+)]
+#[axum::debug_handler]
+async fn svg_template(
+    State(state): State<AppState>,
+    Path(TemplatePath { id, version }): Path<TemplatePath>,
+    Query(query): Query<SvgQuery>,
+    headers: HeaderMap,
+    Json(payload): Json<CompilationPayload>,
+) -> impl IntoResponse {
+    compile_and_render(
+        state,
+        id,
+        version,
+        images(query.options(), &headers),
+        payload,
+    )
+    .await
+}
+
+#[utoipa::path(
+    method(post),
+    tag = crate::TEMPLATE_TAG,
+    path = "/{template_id}/versions/{version}/render",
+    params(
+        ("template_id" = String, example = "invoice", description = TEMPLATE_ID_DESCRIPTION),
+        ("version" = String, example = "0.1.0", description = VERSION_DESCRIPTION),
+        RenderQuery
+    ),
+    request_body(content = CompilationPayload, description = PAYLOAD_DESCRIPTION, content_type = "application/json"),
+    description = "Render a template with given inputs. The format is chosen by the `format` parameter or the `Accept` header: `application/pdf` (default), `image/png`, `image/svg+xml`, or `application/json` for a report of the compilation without exporting.",
+    responses(
+        (status = OK, description = "Success. If the compilation produced warnings, the `x-compilation-warnings` header holds their number and the `link` header points to them. JSON reports contain the warnings instead.", content(
+            ("application/pdf"),
+            ("image/png"),
+            ("image/svg+xml"),
+            (CompilationReport = "application/json"),
+            ("application/zip"),
+            ("multipart/mixed")
+        )),
+        (status = BAD_REQUEST, description = PDF_FAILURE_DESCRIPTION, content(
+            (CompilationFailureResponse = "application/json"),
+            (String = "text/plain")
+        )),
+        (status = NOT_ACCEPTABLE, description = "The `Accept` header lists no supported type"),
+        (status = UNPROCESSABLE_ENTITY, description = INVALID_INPUTS_DESCRIPTION, content(
+            (InvalidInputsResponse = "application/json"),
+            (String = "text/plain")
+        ))
+    )
+)]
+#[axum::debug_handler]
+async fn render_template(
+    State(state): State<AppState>,
+    Path(TemplatePath { id, version }): Path<TemplatePath>,
+    Query(query): Query<RenderQuery>,
+    headers: HeaderMap,
+    Json(payload): Json<CompilationPayload>,
+) -> impl IntoResponse {
+    compile_and_render(state, id, version, query.output(&headers), payload).await
+}
+
+/// Export a PDF with the given options, for routes that always produce PDFs.
+pub(super) fn pdf(query: PdfQuery) -> Result<RenderOutput, RenderRequestError> {
+    parse_pages(query.pages)
+        .map(|pages| RenderOutput::Pdf { pages })
+        .map_err(RenderRequestError::Invalid)
+}
+
+/// Render images with the given options, for routes that always produce images.
+fn images(
+    options: Result<ImageOptions, String>,
+    headers: &HeaderMap,
+) -> Result<RenderOutput, RenderRequestError> {
+    options
+        .map(|options| RenderOutput::Images {
+            options,
+            multipart: accepts_multipart(headers),
+        })
+        .map_err(RenderRequestError::Invalid)
+}
+
+/// Page count and warnings of a successful compilation
+#[derive(ToSchema, Serialize)]
+struct CompilationReport {
+    #[schema(example = 3)]
+    pages: usize,
+    /// Warnings of the compilation
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// A rendered template with the headers describing it
+pub(super) struct Rendered {
+    pub(super) content_type: String,
+    pub(super) disposition: Option<String>,
+    pub(super) body: Vec<u8>,
+}
+
+/// Compile a template with the inputs of a request and render it.
+async fn compile_and_render(
+    state: AppState,
+    id: String,
+    version: String,
+    output: Result<RenderOutput, RenderRequestError>,
+    payload: CompilationPayload,
+) -> Result<impl IntoResponse, TemplateError> {
+    let key = state.resolve(id, &version)?;
+    let (rendered, warnings) = render_version(&state, &key, output, payload).await?;
+    let warnings = state.warnings.record(&key.id, warnings);
+
+    let mut headers = vec![(header::CONTENT_TYPE, rendered.content_type)];
+    if let Some(disposition) = rendered.disposition {
+        headers.push((header::CONTENT_DISPOSITION, disposition));
+    }
+
+    Ok((AppendHeaders(headers), warnings, Body::from(rendered.body)))
+}
+
+/// Compile a template version with the inputs of a request and render it.
+///
+/// Returns the rendered warnings of the compilation along with the output.
+pub(super) async fn render_version(
+    state: &AppState,
+    key: &TemplateKey,
+    output: Result<RenderOutput, RenderRequestError>,
+    payload: CompilationPayload,
+) -> Result<(Rendered, Option<String>), TemplateError> {
+    let render = prepare_render(state, key, output, &payload.pdf_standards).await?;
+    render.run(state, payload).await
+}
+
+/// A render whose options were checked against the template, ready to compile
+pub(super) struct PreparedRender {
+    id: String,
+    pool: CachedTemplate,
+    output: RenderOutput,
+    standards: Vec<PdfStandard>,
+    /// Export failures with other standards than declared are likely caused by those standards
+    overridden: bool,
+}
+
+/// Check the output and the requested PDF standards of a render against the template.
+///
+/// Errors are known before compiling, so requests can fail before any work is queued.
+pub(super) async fn prepare_render(
+    state: &AppState,
+    key: &TemplateKey,
+    output: Result<RenderOutput, RenderRequestError>,
+    pdf_standards: &[String],
+) -> Result<PreparedRender, TemplateError> {
+    let id = key.id.clone();
+    let output = output.map_err(|error| match error {
+        RenderRequestError::NotAcceptable(accept) => TemplateError::NotAcceptable {
+            id: id.clone(),
+            accept,
+        },
+        RenderRequestError::Invalid(error) => TemplateError::InvalidRenderOptions {
+            id: id.clone(),
+            error,
+        },
+    })?;
+    let pool = state.template_pool(key).await?;
+    let declared_standards = &pool.manifest().tool.oicana.export.pdf.standards;
+    let standards =
+        match &output {
+            RenderOutput::Pdf { .. } => export_standards(declared_standards, pdf_standards)
+                .map_err(|error| TemplateError::InvalidPdfStandards {
+                    id: id.clone(),
+                    error,
+                })?,
+            _ => Vec::new(),
+        };
+    let overridden = standards != *declared_standards;
+    if let RenderOutput::Pdf { pages: Some(_) } = &output
+        && requires_tags(&standards)
+    {
+        return Err(TemplateError::InvalidRenderOptions {
+            error: format!(
+                "Template '{id}' is exported as tagged PDF to conform to the selected PDF standards. Tagged PDFs always contain the whole document, so pages cannot be selected."
+            ),
+            id,
+        });
+    }
+
+    Ok(PreparedRender {
+        id,
+        pool,
+        output,
+        standards,
+        overridden,
+    })
+}
+
+impl PreparedRender {
+    /// Compile the template with the inputs of a request and render it.
+    pub(super) async fn run(
+        self,
+        state: &AppState,
+        payload: CompilationPayload,
+    ) -> Result<(Rendered, Option<String>), TemplateError> {
+        let PreparedRender {
+            id,
+            pool,
+            output,
+            standards,
+            overridden,
+        } = self;
+        let inputs = state.template_inputs(&id, &pool, payload)?;
+        let mut template = state.checkout(&id, &pool).await?;
+
+        state
+            .run(&id, {
+                let id = id.clone();
+                move |cancellation| {
+                    let compilation_result = match template.compile(inputs) {
+                        Ok(document) => document,
+                        Err(error) => return Err(TemplateError::CompilationFailure { id, error }),
+                    };
+                    if cancellation.is_cancelled() {
+                        return Err(TemplateError::Cancelled(id));
+                    }
+
+                    let document = &compilation_result.document;
+                    let rendered = match output {
+                        RenderOutput::Pdf { pages } => {
+                            let pages = pages
+                                .map(|pages| pages.indices(document.pages.len()))
+                                .transpose()
+                                .map_err(|error| TemplateError::PageOutOfRange {
+                                    id: id.clone(),
+                                    error,
+                                })?;
+                            Rendered {
+                                content_type: "application/pdf".to_owned(),
+                                disposition: Some(format!("attachment; filename=\"{id}.pdf\"")),
+                                body: export_pdf(document, &*template, &standards, pages.as_deref())
+                                    .map_err(|error| {
+                                        if overridden {
+                                            TemplateError::InvalidPdfStandards {
+                                                id: id.clone(),
+                                                error: format!(
+                                                    "The template does not conform to the requested PDF standards.\n{error}"
+                                                ),
+                                            }
+                                        } else {
+                                            TemplateError::ExportFailure {
+                                                id: id.clone(),
+                                                error,
+                                            }
+                                        }
+                                    })?,
+                            }
+                        }
+                        RenderOutput::Images { options, multipart } => {
+                            let images = render(&id, document, &options, multipart).map_err(
+                                |error| match error {
+                                    RenderError::Pages(error) => TemplateError::PageOutOfRange {
+                                        id: id.clone(),
+                                        error,
+                                    },
+                                    RenderError::Export(error) => TemplateError::ExportFailure {
+                                        id: id.clone(),
+                                        error,
+                                    },
+                                },
+                            )?;
+                            Rendered {
+                                content_type: images.content_type,
+                                disposition: Some(images.disposition),
+                                body: images.body,
+                            }
+                        }
+                        RenderOutput::Report => {
+                            let report = CompilationReport {
+                                pages: document.pages.len(),
+                                diagnostics: compilation_result
+                                    .warnings
+                                    .as_deref()
+                                    .map(parse_rendered)
+                                    .unwrap_or_default(),
+                            };
+                            return Ok((
+                                Rendered {
+                                    content_type: "application/json".to_owned(),
+                                    disposition: None,
+                                    body: serde_json::to_vec(&report)
+                                        .expect("The report is valid JSON"),
+                                },
+                                // Part of the report instead of the headers
+                                None,
+                            ));
+                        }
+                    };
+
+                    Ok((rendered, compilation_result.warnings))
+                }
+            })
+            .await
+    }
+}
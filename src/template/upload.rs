@@ -0,0 +1,164 @@
+use axum::{
+    extract::{Multipart, Path, State},
+    http::StatusCode,
+};
+use semver::Version;
+use tracing::info;
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+use uuid::Uuid;
+
+use super::{AppState, TemplateError};
+use crate::cache::{TemplateKey, read_manifest};
+
+/// Routes that add templates to the service
+pub(super) fn router() -> OpenApiRouter<AppState> {
+    OpenApiRouter::new().routes(routes!(upload_template))
+}
+
+#[derive(ToSchema)]
+#[schema(title = "TemplateUpload")]
+#[allow(dead_code)]
+struct TemplateUploadSchema {
+    /// The packed template (zip file) to upload
+    #[schema(value_type = String, format = Binary)]
+    file: Vec<u8>,
+}
+
+#[utoipa::path(
+    method(put),
+    tag = crate::TEMPLATE_TAG,
+    path = "/{template_id}/{version}",
+    params(
+        ("template_id" = String, example = "table", description = "The identifier of the uploaded template. Must match the package name in its manifest."),
+        ("version" = String, example = "0.1.0", description = "The version of the uploaded template. Must match the package version in its manifest.")
+    ),
+    request_body(content = TemplateUploadSchema, content_type = "multipart/form-data"),
+    description = "Upload a packed template. The template is validated and replaces the cached template with the same ID.",
+    responses(
+        (status = NO_CONTENT, description = "Template stored and registered"),
+        (status = BAD_REQUEST, description = "The upload is not a valid packed template for the given ID and version"),
+        (status = INTERNAL_SERVER_ERROR, description = "Failed to save the template to disk")
+    )
+)]
+async fn upload_template(
+    State(state): State<AppState>,
+    Path((id, version)): Path<(String, String)>,
+    mut multipart: Multipart,
+) -> Result<StatusCode, TemplateError> {
+    let mut file_data: Option<Vec<u8>> = None;
+
+    while let Some(field) =
+        multipart
+            .next_field()
+            .await
+            .map_err(|error| TemplateError::InvalidUpload {
+                id: id.clone(),
+                error: format!("Failed to read the upload: {error}"),
+            })?
+    {
+        if field.name() == Some("file") {
+            match field.bytes().await {
+                Ok(bytes) => {
+                    file_data = Some(bytes.to_vec());
+                    break;
+                }
+                Err(error) => {
+                    return Err(TemplateError::InvalidUpload {
+                        id,
+                        error: format!("Failed to read file: {error}"),
+                    });
+                }
+            }
+        }
+    }
+
+    let Some(data) = file_data else {
+        return Err(TemplateError::InvalidUpload {
+            id,
+            error: "No file field provided".to_owned(),
+        });
+    };
+
+    let version = match Version::parse(&version) {
+        Ok(version) => version,
+        Err(error) => {
+            return Err(TemplateError::InvalidVersion {
+                id,
+                version,
+                error: error.to_string(),
+            });
+        }
+    };
+    // Check the manifest before preparing the instances of the pool, which is expensive
+    let manifest = match read_manifest(&data) {
+        Ok(manifest) => manifest,
+        Err(error) => {
+            return Err(TemplateError::InvalidUpload {
+                id,
+                error: format!("{error:#}"),
+            });
+        }
+    };
+    if let Err(error) = manifest.validate() {
+        return Err(TemplateError::InvalidUpload {
+            id,
+            error: error.to_string(),
+        });
+    }
+    if manifest.package.name != id.as_str()
+        || manifest.package.version.to_string() != version.to_string()
+    {
+        let error = format!(
+            "The manifest describes '{}' v{}, but the upload is for '{id}' v{version}",
+            manifest.package.name, manifest.package.version
+        );
+        return Err(TemplateError::InvalidUpload { id, error });
+    }
+
+    let pool = match tokio::task::spawn_blocking({
+        let template_cache = state.template_cache.clone();
+        let id = id.clone();
+        let data = data.clone();
+        move || template_cache.prepare(&id, data)
+    })
+    .await
+    {
+        Ok(Ok(pool)) => pool,
+        Ok(Err(error)) => {
+            return Err(TemplateError::InvalidUpload {
+                id,
+                error: format!("{error:#}"),
+            });
+        }
+        Err(error) => {
+            return Err(TemplateError::InvalidUpload {
+                id,
+                error: error.to_string(),
+            });
+        }
+    };
+
+    // Write to a temporary file first, so a partially written upload never replaces a
+    // template. Concurrent uploads of the same version each write a file of their own.
+    let key = TemplateKey { id, version };
+    let path = state.template_cache.path(&key);
+    let temporary_path = path.with_extension(format!("zip.{}.upload", Uuid::new_v4()));
+    if let Err(error) = tokio::fs::write(&temporary_path, &data).await {
+        return Err(TemplateError::StorageFailure {
+            id: key.id,
+            error: error.to_string(),
+        });
+    }
+    if let Err(error) = tokio::fs::rename(&temporary_path, &path).await {
+        return Err(TemplateError::StorageFailure {
+            id: key.id,
+            error: error.to_string(),
+        });
+    }
+
+    info!("Stored template {key} and registered it in the cache");
+    state.template_cache.register_upload(key, pool, &data);
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,154 @@
+use std::time::Instant;
+
+use axum::{
+    Json,
+    extract::{Path, State},
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+use utoipa_axum::{router::OpenApiRouter, routes};
+
+use super::{
+    AppState, CompilationPayload, PAYLOAD_DESCRIPTION, TEMPLATE_ID_DESCRIPTION, TemplateError,
+    TemplatePath, VERSION_DESCRIPTION, with_latest,
+};
+use crate::{
+    diagnostics::{Diagnostic, parse, parse_rendered},
+    schema::SchemaViolation,
+};
+
+/// Routes that validate inputs without exporting the template
+pub(super) fn router() -> OpenApiRouter<AppState> {
+    OpenApiRouter::new().routes(with_latest(routes!(validate_template)))
+}
+
+/// Result of validating inputs without exporting the template
+#[derive(ToSchema, Serialize)]
+struct ValidationReport {
+    /// Whether the inputs match their schemas and the template compiles with them
+    success: bool,
+    /// Number of pages of the compiled document
+    #[schema(example = 3)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pages: Option<usize>,
+    /// Number of warnings of the compilation
+    #[schema(example = 0)]
+    warnings: usize,
+    /// Errors and warnings of the compilation, errors first
+    diagnostics: Vec<Diagnostic>,
+    /// JSON inputs that do not match their schemas. The template is not compiled if there
+    /// are any.
+    violations: Vec<SchemaViolation>,
+    timing: ValidationTiming,
+}
+
+/// Duration of a validation
+#[derive(ToSchema, Serialize)]
+struct ValidationTiming {
+    /// Time spent compiling the template in milliseconds
+    #[schema(example = 84.2)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compile_ms: Option<f64>,
+    /// Time spent on the whole request in milliseconds, including waiting for a template
+    /// instance and a compile thread
+    #[schema(example = 90.5)]
+    total_ms: f64,
+}
+
+#[utoipa::path(
+    method(post),
+    tag = crate::TEMPLATE_TAG,
+    path = "/{template_id}/versions/{version}/validate",
+    params(
+        ("template_id" = String, example = "invoice", description = TEMPLATE_ID_DESCRIPTION),
+        ("version" = String, example = "0.1.0", description = VERSION_DESCRIPTION)
+    ),
+    request_body(content = CompilationPayload, description = PAYLOAD_DESCRIPTION, content_type = "application/json"),
+    description = "Validate inputs for a template. Checks the inputs against their schemas and compiles the template, but does not export it. Use this to check inputs quickly before rendering.",
+    responses(
+        (status = OK, description = "The result of the validation. Inputs that do not match their schemas or fail to compile are reported with `success` set to `false`.", body = ValidationReport, content_type = "application/json"),
+        (status = BAD_REQUEST, description = "The request lacks inputs that the template has no value for")
+    )
+)]
+#[axum::debug_handler]
+async fn validate_template(
+    State(state): State<AppState>,
+    Path(TemplatePath { id, version }): Path<TemplatePath>,
+    Json(payload): Json<CompilationPayload>,
+) -> Result<Json<ValidationReport>, TemplateError> {
+    let start = Instant::now();
+    let key = state.resolve(id, &version)?;
+    let id = key.id.clone();
+    let pool = state.template_pool(&key).await?;
+    let inputs = match state.template_inputs(&id, &pool, payload) {
+        Ok(inputs) => inputs,
+        Err(TemplateError::InvalidInputs { violations, .. }) => {
+            return Ok(Json(ValidationReport {
+                success: false,
+                pages: None,
+                warnings: 0,
+                diagnostics: Vec::new(),
+                violations,
+                timing: ValidationTiming {
+                    compile_ms: None,
+                    total_ms: milliseconds(start),
+                },
+            }));
+        }
+        Err(error) => return Err(error),
+    };
+    let mut template = state.checkout(&id, &pool).await?;
+
+    let (result, compile_ms) = state
+        .run(&id, move |_| {
+            let compile_start = Instant::now();
+            let result = template.compile(inputs);
+            Ok((result, milliseconds(compile_start)))
+        })
+        .await?;
+
+    let report = match result {
+        Ok(compilation_result) => {
+            let diagnostics = compilation_result
+                .warnings
+                .as_deref()
+                .map(parse_rendered)
+                .unwrap_or_default();
+            ValidationReport {
+                success: true,
+                pages: Some(compilation_result.document.pages.len()),
+                warnings: diagnostics.len(),
+                diagnostics,
+                violations: Vec::new(),
+                timing: ValidationTiming {
+                    compile_ms: Some(compile_ms),
+                    total_ms: milliseconds(start),
+                },
+            }
+        }
+        Err(failure) => {
+            let diagnostics = parse(&failure);
+            ValidationReport {
+                success: false,
+                pages: None,
+                warnings: diagnostics
+                    .iter()
+                    .filter(|diagnostic| diagnostic.is_warning())
+                    .count(),
+                diagnostics,
+                violations: Vec::new(),
+                timing: ValidationTiming {
+                    compile_ms: Some(compile_ms),
+                    total_ms: milliseconds(start),
+                },
+            }
+        }
+    };
+
+    Ok(Json(report))
+}
+
+/// Milliseconds since the given instant.
+fn milliseconds(start: Instant) -> f64 {
+    start.elapsed().as_secs_f64() * 1000.0
+}
@@ -0,0 +1,131 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    Json,
+    extract::{MatchedPath, RawPathParams, Request, State, rejection::RawPathParamsRejection},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use tracing::error;
+
+use crate::config::TimeoutConfig;
+
+/// Path parameter that names the template of a request.
+const TEMPLATE_ID_PARAMETER: &str = "template_id";
+
+/// Cancel requests that take longer than their configured time limit.
+///
+/// Dropping the request stops its work. Compile jobs that did not start yet are skipped
+/// and running compile jobs stop before exporting. Typst cannot interrupt a running
+/// compilation though, so its template instance stays checked out until it finished.
+pub async fn limit_duration(
+    State(timeouts): State<Arc<TimeoutConfig>>,
+    matched_path: Option<MatchedPath>,
+    path_parameters: Result<RawPathParams, RawPathParamsRejection>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = matched_path.map(|path| format!("{} {}", request.method(), path.as_str()));
+    let template_id = path_parameters.ok().and_then(|parameters| {
+        parameters
+            .iter()
+            .find(|(name, _)| *name == TEMPLATE_ID_PARAMETER)
+            .map(|(_, value)| value.to_owned())
+    });
+    let limit = timeouts.limit(route.as_deref(), template_id.as_deref());
+
+    match tokio::time::timeout(limit, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => cancelled(route, template_id, limit),
+    }
+}
+
+fn cancelled(route: Option<String>, template_id: Option<String>, limit: Duration) -> Response {
+    #[derive(Serialize)]
+    struct ErrorResponse {
+        message: String,
+    }
+
+    let limit_ms = limit.as_millis();
+    let route = route.unwrap_or_default();
+    let message = match template_id {
+        Some(template_id) => {
+            error!(%template_id, "{route} for template '{template_id}' was cancelled after {limit_ms} ms");
+            format!(
+                "Request for template '{template_id}' was cancelled because it took longer than {limit_ms} ms. A compilation that already started still finishes, but is not exported."
+            )
+        }
+        None => {
+            error!("{route} was cancelled after {limit_ms} ms");
+            format!("Request was cancelled because it took longer than {limit_ms} ms.")
+        }
+    };
+
+    (StatusCode::REQUEST_TIMEOUT, Json(ErrorResponse { message })).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs, thread};
+
+    use axum::{Router, body::Body, middleware, routing::get};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{cache::init_template, pool::TemplatePool, worker::CompilePool};
+
+    /// Time a compile job holds its template instance, longer than the time limit
+    const COMPILATION: Duration = Duration::from_millis(300);
+
+    #[tokio::test]
+    async fn timed_out_requests_free_their_template_instance() {
+        let bytes = fs::read("templates/table-0.1.0.zip").unwrap();
+        let pool = Arc::new(TemplatePool::new(vec![init_template(bytes).unwrap()], 10));
+        let compile_pool = Arc::new(CompilePool::new(1));
+
+        let handler = {
+            let pool = pool.clone();
+            move || async move {
+                let template = pool.checkout().await.unwrap();
+                compile_pool
+                    .run(move |_| {
+                        // Stands in for a compilation, which cannot be interrupted
+                        let _template = template;
+                        thread::sleep(COMPILATION);
+                    })
+                    .await
+                    .unwrap();
+                StatusCode::OK
+            }
+        };
+        let timeouts = Arc::new(TimeoutConfig {
+            default: 100,
+            routes: HashMap::new(),
+            templates: HashMap::new(),
+        });
+        let app = Router::new()
+            .route("/templates/{template_id}", get(handler))
+            .layer(middleware::from_fn_with_state(timeouts, limit_duration));
+        let request = || {
+            app.clone().oneshot(
+                Request::get("/templates/table")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+        };
+
+        let (first, second) = tokio::join!(request(), request());
+        assert_eq!(first.unwrap().status(), StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(second.unwrap().status(), StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(pool.stats().waiting, 0);
+
+        // The running compilation still holds the instance until it finished
+        assert_eq!(pool.stats().available, 0);
+        tokio::time::sleep(COMPILATION).await;
+        assert_eq!(pool.stats().available, 1);
+        let template = tokio::time::timeout(Duration::from_millis(10), pool.checkout()).await;
+        assert!(matches!(template, Ok(Ok(_))));
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+use axum::{
+    http::{HeaderName, HeaderValue, header},
+    response::{IntoResponseParts, ResponseParts},
+};
+use tracing::warn;
+use uuid::Uuid;
+
+/// Header with the number of warnings of a successful compilation.
+const WARNING_COUNT_HEADER: HeaderName = HeaderName::from_static("x-compilation-warnings");
+
+/// Warnings of recent compilations.
+///
+/// Successful responses only carry the number of warnings and a link to them. The warnings
+/// of the most recent compilations are kept, older ones are dropped.
+pub struct WarningStore {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    warnings: HashMap<Uuid, StoredWarnings>,
+    /// Oldest entries first
+    order: VecDeque<Uuid>,
+}
+
+struct StoredWarnings {
+    template_id: String,
+    rendered: String,
+}
+
+/// Warnings of a compilation, added to a response as headers.
+///
+/// Warnings of several compilations are combined by collecting them, then the count is the
+/// total and every kept set of warnings gets a link.
+pub struct CompilationWarnings {
+    count: usize,
+    links: Vec<String>,
+}
+
+impl WarningStore {
+    /// Keep the warnings of up to `capacity` compilations.
+    pub fn new(capacity: usize) -> Self {
+        WarningStore {
+            capacity,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Keep the warnings of a successful compilation and log them.
+    pub fn record(&self, template_id: &str, warnings: Option<String>) -> CompilationWarnings {
+        let Some(rendered) = warnings.filter(|rendered| !rendered.trim().is_empty()) else {
+            return CompilationWarnings {
+                count: 0,
+                links: Vec::new(),
+            };
+        };
+        warn!(%template_id, "Template '{template_id}' compiled with warnings: {rendered}");
+        // Warnings that cannot be parsed still exist, so they count as one
+        let count = crate::diagnostics::parse_rendered(&rendered).len().max(1);
+        if self.capacity == 0 {
+            return CompilationWarnings {
+                count,
+                links: Vec::new(),
+            };
+        }
+
+        let id = Uuid::new_v4();
+        let mut entries = self
+            .entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        while entries.order.len() >= self.capacity {
+            if let Some(oldest) = entries.order.pop_front() {
+                entries.warnings.remove(&oldest);
+            }
+        }
+        entries.order.push_back(id);
+        entries.warnings.insert(
+            id,
+            StoredWarnings {
+                template_id: template_id.to_owned(),
+                rendered,
+            },
+        );
+
+        CompilationWarnings {
+            count,
+            links: vec![format!("/templates/{template_id}/warnings/{id}")],
+        }
+    }
+
+    /// Rendered warnings of a compilation of the given template, if they are still kept.
+    pub fn get(&self, template_id: &str, id: Uuid) -> Option<String> {
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .warnings
+            .get(&id)
+            .filter(|warnings| warnings.template_id == template_id)
+            .map(|warnings| warnings.rendered.clone())
+    }
+}
+
+impl IntoResponseParts for CompilationWarnings {
+    type Error = std::convert::Infallible;
+
+    fn into_response_parts(
+        self,
+        mut response: ResponseParts,
+    ) -> Result<ResponseParts, Self::Error> {
+        if self.count == 0 {
+            return Ok(response);
+        }
+
+        let headers = response.headers_mut();
+        headers.insert(WARNING_COUNT_HEADER, HeaderValue::from(self.count));
+        let links: Vec<String> = self
+            .links
+            .iter()
+            .map(|link| format!("<{link}>; rel=\"warnings\""))
+            .collect();
+        if !links.is_empty()
+            && let Ok(value) = HeaderValue::from_str(&links.join(", "))
+        {
+            headers.insert(header::LINK, value);
+        }
+
+        Ok(response)
+    }
+}
+
+impl FromIterator<CompilationWarnings> for CompilationWarnings {
+    fn from_iter<I: IntoIterator<Item = CompilationWarnings>>(warnings: I) -> Self {
+        warnings.into_iter().fold(
+            CompilationWarnings {
+                count: 0,
+                links: Vec::new(),
+            },
+            |mut combined, warnings| {
+                combined.count += warnings.count;
+                combined.links.extend(warnings.links);
+                combined
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::IntoResponse;
+
+    use super::*;
+
+    const WARNING: &str = r#"warning: unknown font family: unknown
+  ┌─ /main.typ:1:17
+  │
+1 │ #set text(font: "unknown")
+  │                 ^^^^^^^^^
+
+"#;
+
+    fn headers(warnings: CompilationWarnings) -> axum::http::HeaderMap {
+        (warnings, "").into_response().headers().clone()
+    }
+
+    #[test]
+    fn counts_and_links_recorded_warnings() {
+        let store = WarningStore::new(10);
+
+        let headers = headers(store.record("table", Some(WARNING.repeat(2))));
+
+        assert_eq!(headers[WARNING_COUNT_HEADER], "2");
+        let link = headers[header::LINK].to_str().unwrap();
+        assert!(link.starts_with("</templates/table/warnings/"), "{link}");
+    }
+
+    #[test]
+    fn counts_unparsable_warnings_as_one() {
+        let store = WarningStore::new(10);
+
+        let headers = headers(store.record("table", Some("something went wrong".to_owned())));
+
+        assert_eq!(headers[WARNING_COUNT_HEADER], "1");
+        assert!(headers.contains_key(header::LINK));
+    }
+
+    #[test]
+    fn ignores_compilations_without_warnings() {
+        let store = WarningStore::new(10);
+
+        for warnings in [None, Some("\n".to_owned())] {
+            let headers = headers(store.record("table", warnings));
+            assert!(!headers.contains_key(WARNING_COUNT_HEADER));
+            assert!(!headers.contains_key(header::LINK));
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use std::{collections::HashSet, path::PathBuf, sync::Arc, time::Duration};
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel};
+use tracing::{error, info};
+
+use crate::cache::TemplateCache;
+
+/// Time without further changes before changed templates are reloaded.
+///
+/// Copying or saving a packed template usually triggers several events in quick succession.
+const DEBOUNCE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Watch the templates directory and reload packed templates when their files change.
+///
+/// Watching stops when the returned watcher is dropped.
+pub fn watch_templates(
+    directory: PathBuf,
+    template_cache: Arc<TemplateCache>,
+) -> notify::Result<RecommendedWatcher> {
+    let (sender, receiver) = unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |result: notify::Result<Event>| {
+        match result {
+            // Reading a template to reload it causes access events. Those must not trigger
+            // another reload.
+            Ok(event)
+                if matches!(
+                    event.kind,
+                    EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                ) =>
+            {
+                for path in event.paths {
+                    let _ = sender.send(path);
+                }
+            }
+            Ok(_) => {}
+            Err(error) => error!("Failed to watch the templates directory: {error:?}"),
+        }
+    })?;
+    watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+    info!("Watching '{}' for template changes.", directory.display());
+
+    tokio::spawn(reload_changed_templates(receiver, template_cache));
+
+    Ok(watcher)
+}
+
+async fn reload_changed_templates(
+    mut changes: UnboundedReceiver<PathBuf>,
+    template_cache: Arc<TemplateCache>,
+) {
+    while let Some(path) = changes.recv().await {
+        let mut changed = HashSet::from([path]);
+        while let Ok(Some(path)) = tokio::time::timeout(DEBOUNCE_TIMEOUT, changes.recv()).await {
+            changed.insert(path);
+        }
+
+        let template_cache = template_cache.clone();
+        let reload = tokio::task::spawn_blocking(move || {
+            for path in changed {
+                template_cache.reload(&path);
+            }
+        });
+        if let Err(error) = reload.await {
+            error!("Failed to reload changed templates: {error:?}");
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex, mpsc},
+    thread,
+};
+
+use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads dedicated to compiling templates and exporting documents.
+///
+/// Compilation is synchronous and CPU heavy. Running it on these threads keeps the
+/// async runtime free to serve other requests.
+pub struct CompilePool {
+    jobs: mpsc::Sender<Job>,
+}
+
+/// A job panicked before it could return a result.
+#[derive(Debug)]
+pub struct JobPanicked;
+
+impl CompilePool {
+    /// Start a pool with the given number of threads.
+    pub fn new(threads: usize) -> Self {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for index in 0..threads {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("compile-{index}"))
+                .spawn(move || {
+                    loop {
+                        let job = match receiver.lock() {
+                            Ok(receiver) => receiver.recv(),
+                            Err(_) => break,
+                        };
+                        match job {
+                            Ok(job) => job(),
+                            Err(_) => break,
+                        }
+                    }
+                })
+                .expect("Failed to start compile thread");
+        }
+
+        CompilePool { jobs }
+    }
+
+    /// Run a job on the pool and wait for its result.
+    ///
+    /// If the returned future is dropped, a job that did not start yet is skipped and the
+    /// token passed to a running job is cancelled. Jobs should check the token between
+    /// expensive steps.
+    pub async fn run<T, F>(&self, job: F) -> Result<T, JobPanicked>
+    where
+        T: Send + 'static,
+        F: FnOnce(&CancellationToken) -> T + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let cancellation = CancellationToken::new();
+        let _cancel_on_drop = cancellation.clone().drop_guard();
+        let job: Job = Box::new(move || {
+            if cancellation.is_cancelled() {
+                debug!("Skipping a cancelled compile job");
+                return;
+            }
+            match panic::catch_unwind(AssertUnwindSafe(|| job(&cancellation))) {
+                Ok(result) => {
+                    let _ = sender.send(result);
+                }
+                Err(_) => error!("A compile job panicked"),
+            };
+        });
+        if self.jobs.send(job).is_err() {
+            error!("All compile threads stopped");
+            return Err(JobPanicked);
+        }
+
+        receiver.await.map_err(|_| JobPanicked)
+    }
+}